@@ -0,0 +1,11 @@
+-- API keys are stored as the sha256 of the raw key, e.g.:
+-- INSERT INTO api_keys (name, key_hash, client_ids) VALUES ('partner', sha256('raw-key'::bytea), '{1,2}');
+-- INSERT INTO api_keys (name, key_hash, is_admin) VALUES ('ops', sha256('raw-admin-key'::bytea), TRUE);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  key_hash BYTEA NOT NULL UNIQUE,
+  client_ids INT[] DEFAULT '{}' NOT NULL,
+  is_admin BOOLEAN DEFAULT FALSE NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Token buckets shared by every API instance when rate_limit.shared is enabled
+
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limits (
+  key TEXT PRIMARY KEY,
+  tokens DOUBLE PRECISION NOT NULL,
+  allowed BOOLEAN NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL
+);
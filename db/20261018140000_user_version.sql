@@ -0,0 +1,3 @@
+-- Bumped on every update, the optimistic update strategy only writes rows whose version it read
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS version BIGINT DEFAULT 0 NOT NULL;
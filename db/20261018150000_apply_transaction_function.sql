@@ -0,0 +1,109 @@
+-- Applies a transaction to an account in a single statement, used by the "function" update strategy.
+-- encoded_transactions is the bincode encoding of [Transaction; 10] written by the API:
+-- per transaction valor as a little endian i32, then descricao, tipo and realizada_em as
+-- little endian u64 byte lengths followed by their UTF-8 bytes.
+
+CREATE OR REPLACE FUNCTION bincode_le(value BIGINT, size INT) RETURNS BYTEA
+LANGUAGE plpgsql IMMUTABLE AS $$
+DECLARE
+  encoded BYTEA := '';
+BEGIN
+  FOR i IN 0 .. size - 1 LOOP
+    encoded := encoded || set_byte('\x00'::BYTEA, 0, ((value >> (8 * i)) & 255)::INT);
+  END LOOP;
+  RETURN encoded;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION bincode_string(value TEXT) RETURNS BYTEA
+LANGUAGE sql IMMUTABLE AS $$
+  SELECT bincode_le(octet_length(convert_to(value, 'UTF8')), 8) || convert_to(value, 'UTF8');
+$$;
+
+-- result is 'ok', 'not_found', 'limit_exceeded' or 'invalid_kind'. balance and balance_limit
+-- are the account's after the transaction, or as they were when it was refused.
+CREATE OR REPLACE FUNCTION apply_transaction(
+  account_id INT,
+  valor INT,
+  tipo TEXT,
+  descricao TEXT,
+  realizada_em TEXT
+) RETURNS TABLE (result TEXT, balance INT, balance_limit INT)
+LANGUAGE plpgsql AS $$
+DECLARE
+  account users%ROWTYPE;
+  ring BYTEA;
+  slot INT;
+  slot_start INT;
+  slot_end INT;
+  offset_in_ring INT := 0;
+  field_length BIGINT;
+BEGIN
+  IF tipo NOT IN ('c', 'd') THEN
+    RETURN QUERY SELECT 'invalid_kind', NULL::INT, NULL::INT;
+    RETURN;
+  END IF;
+
+  SELECT * INTO account FROM users WHERE id = account_id FOR UPDATE;
+  IF NOT FOUND THEN
+    RETURN QUERY SELECT 'not_found', NULL::INT, NULL::INT;
+    RETURN;
+  END IF;
+  IF tipo = 'd' AND account.balance - valor < -account.balance_limit THEN
+    RETURN QUERY SELECT 'limit_exceeded', account.balance, account.balance_limit;
+    RETURN;
+  END IF;
+
+  -- Same ring buffer bookkeeping as User::add_transaction
+  IF account.transactions_count < 10 THEN
+    slot := account.transactions_count;
+    account.transactions_count := account.transactions_count + 1;
+    account.last_transaction := account.transactions_count % 10;
+  ELSE
+    slot := account.last_transaction;
+    account.last_transaction := (account.last_transaction + 1) % 10;
+  END IF;
+
+  -- A missing array decodes to 10 default transactions
+  ring := account.encoded_transactions;
+  IF ring IS NULL THEN
+    ring := '';
+    FOR i IN 1 .. 10 LOOP
+      ring := ring || bincode_le(0, 4) || bincode_string('') || bincode_string('')
+        || bincode_string('1970-01-01T00:00:00.000000Z');
+    END LOOP;
+  END IF;
+
+  FOR i IN 0 .. 9 LOOP
+    IF i = slot THEN
+      slot_start := offset_in_ring;
+    END IF;
+    offset_in_ring := offset_in_ring + 4;
+    FOR field IN 1 .. 3 LOOP
+      field_length := 0;
+      FOR b IN 0 .. 7 LOOP
+        field_length := field_length + (get_byte(ring, offset_in_ring + b)::BIGINT << (8 * b));
+      END LOOP;
+      offset_in_ring := offset_in_ring + 8 + field_length::INT;
+    END LOOP;
+    IF i = slot THEN
+      slot_end := offset_in_ring;
+    END IF;
+  END LOOP;
+
+  ring := substring(ring FROM 1 FOR slot_start)
+    || bincode_le(valor, 4) || bincode_string(descricao) || bincode_string(tipo)
+    || bincode_string(realizada_em)
+    || substring(ring FROM slot_end + 1);
+
+  RETURN QUERY
+  UPDATE users SET
+    balance = users.balance + CASE WHEN tipo = 'c' THEN valor ELSE -valor END,
+    transactions_count = account.transactions_count,
+    last_transaction = account.last_transaction,
+    encoded_transactions = ring,
+    version = users.version + 1
+  WHERE users.id = account_id
+  RETURNING 'ok'::TEXT, users.balance, users.balance_limit;
+END;
+$$;
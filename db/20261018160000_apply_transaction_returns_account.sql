@@ -0,0 +1,102 @@
+-- apply_transaction also returns the account's ring buffer and version, so callers can keep
+-- the whole account, like the statement cache does. The return type changes, so it is recreated.
+
+DROP FUNCTION IF EXISTS apply_transaction(INT, INT, TEXT, TEXT, TEXT);
+
+-- result is 'ok', 'not_found', 'limit_exceeded' or 'invalid_kind'. The other columns are the
+-- account's row after the transaction, only balance and balance_limit are set when it was refused.
+CREATE FUNCTION apply_transaction(
+  account_id INT,
+  valor INT,
+  tipo TEXT,
+  descricao TEXT,
+  realizada_em TEXT
+) RETURNS TABLE (
+  result TEXT,
+  balance INT,
+  balance_limit INT,
+  transactions_count INT,
+  last_transaction INT,
+  encoded_transactions BYTEA,
+  version BIGINT
+)
+LANGUAGE plpgsql AS $$
+DECLARE
+  account users%ROWTYPE;
+  ring BYTEA;
+  slot INT;
+  slot_start INT;
+  slot_end INT;
+  offset_in_ring INT := 0;
+  field_length BIGINT;
+BEGIN
+  IF tipo NOT IN ('c', 'd') THEN
+    RETURN QUERY SELECT 'invalid_kind', NULL::INT, NULL::INT, NULL::INT, NULL::INT, NULL::BYTEA, NULL::BIGINT;
+    RETURN;
+  END IF;
+
+  SELECT * INTO account FROM users WHERE id = account_id FOR UPDATE;
+  IF NOT FOUND THEN
+    RETURN QUERY SELECT 'not_found', NULL::INT, NULL::INT, NULL::INT, NULL::INT, NULL::BYTEA, NULL::BIGINT;
+    RETURN;
+  END IF;
+  IF tipo = 'd' AND account.balance - valor < -account.balance_limit THEN
+    RETURN QUERY SELECT 'limit_exceeded', account.balance, account.balance_limit,
+      NULL::INT, NULL::INT, NULL::BYTEA, NULL::BIGINT;
+    RETURN;
+  END IF;
+
+  -- Same ring buffer bookkeeping as User::add_transaction
+  IF account.transactions_count < 10 THEN
+    slot := account.transactions_count;
+    account.transactions_count := account.transactions_count + 1;
+    account.last_transaction := account.transactions_count % 10;
+  ELSE
+    slot := account.last_transaction;
+    account.last_transaction := (account.last_transaction + 1) % 10;
+  END IF;
+
+  -- A missing array decodes to 10 default transactions
+  ring := account.encoded_transactions;
+  IF ring IS NULL THEN
+    ring := '';
+    FOR i IN 1 .. 10 LOOP
+      ring := ring || bincode_le(0, 4) || bincode_string('') || bincode_string('')
+        || bincode_string('1970-01-01T00:00:00.000000Z');
+    END LOOP;
+  END IF;
+
+  FOR i IN 0 .. 9 LOOP
+    IF i = slot THEN
+      slot_start := offset_in_ring;
+    END IF;
+    offset_in_ring := offset_in_ring + 4;
+    FOR field IN 1 .. 3 LOOP
+      field_length := 0;
+      FOR b IN 0 .. 7 LOOP
+        field_length := field_length + (get_byte(ring, offset_in_ring + b)::BIGINT << (8 * b));
+      END LOOP;
+      offset_in_ring := offset_in_ring + 8 + field_length::INT;
+    END LOOP;
+    IF i = slot THEN
+      slot_end := offset_in_ring;
+    END IF;
+  END LOOP;
+
+  ring := substring(ring FROM 1 FOR slot_start)
+    || bincode_le(valor, 4) || bincode_string(descricao) || bincode_string(tipo)
+    || bincode_string(realizada_em)
+    || substring(ring FROM slot_end + 1);
+
+  RETURN QUERY
+  UPDATE users SET
+    balance = users.balance + CASE WHEN tipo = 'c' THEN valor ELSE -valor END,
+    transactions_count = account.transactions_count,
+    last_transaction = account.last_transaction,
+    encoded_transactions = ring,
+    version = users.version + 1
+  WHERE users.id = account_id
+  RETURNING 'ok'::TEXT, users.balance, users.balance_limit, users.transactions_count,
+    users.last_transaction, users.encoded_transactions, users.version;
+END;
+$$;
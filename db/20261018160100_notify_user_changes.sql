@@ -0,0 +1,25 @@
+-- Every change of an account is announced on the users_changed channel, delivered on commit,
+-- so each instance can drop its cached statements. The payload is "id:version" for inserts and
+-- updates, "id" for deletes and "*" for truncates.
+
+CREATE OR REPLACE FUNCTION notify_user_changed() RETURNS TRIGGER
+LANGUAGE plpgsql AS $$
+BEGIN
+  IF TG_OP = 'TRUNCATE' THEN
+    PERFORM pg_notify('users_changed', '*');
+  ELSIF TG_OP = 'DELETE' THEN
+    PERFORM pg_notify('users_changed', OLD.id::TEXT);
+  ELSE
+    PERFORM pg_notify('users_changed', NEW.id || ':' || NEW.version);
+  END IF;
+  RETURN NULL;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS users_changed ON users;
+CREATE TRIGGER users_changed AFTER INSERT OR UPDATE OR DELETE ON users
+FOR EACH ROW EXECUTE FUNCTION notify_user_changed();
+
+DROP TRIGGER IF EXISTS users_truncated ON users;
+CREATE TRIGGER users_truncated AFTER TRUNCATE ON users
+FOR EACH STATEMENT EXECUTE FUNCTION notify_user_changed();
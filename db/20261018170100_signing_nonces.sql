@@ -0,0 +1,12 @@
+-- Nonces of signed requests, shared by every API instance when signing.shared is enabled.
+-- expires_at is the unix time the request's timestamp leaves the window, after which the
+-- nonce can't be replayed anyway and the row is deleted.
+
+CREATE UNLOGGED TABLE IF NOT EXISTS signing_nonces (
+  partner_id TEXT NOT NULL,
+  nonce BYTEA NOT NULL,
+  expires_at BIGINT NOT NULL,
+  PRIMARY KEY (partner_id, nonce)
+);
+
+CREATE INDEX IF NOT EXISTS signing_nonces_expires_at ON signing_nonces (expires_at);
@@ -0,0 +1,15 @@
+-- pg_notify takes a database wide lock at commit, so changes are only announced by sessions
+-- that set rinha.notify_user_changes, which the API does when statement_cache.enabled is set.
+-- Writers without it, like psql, don't invalidate the cached statements.
+
+DROP TRIGGER IF EXISTS users_changed ON users;
+CREATE TRIGGER users_changed AFTER INSERT OR UPDATE OR DELETE ON users
+FOR EACH ROW
+WHEN (current_setting('rinha.notify_user_changes', TRUE) = 'on')
+EXECUTE FUNCTION notify_user_changed();
+
+DROP TRIGGER IF EXISTS users_truncated ON users;
+CREATE TRIGGER users_truncated AFTER TRUNCATE ON users
+FOR EACH STATEMENT
+WHEN (current_setting('rinha.notify_user_changes', TRUE) = 'on')
+EXECUTE FUNCTION notify_user_changed();
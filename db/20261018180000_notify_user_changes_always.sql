@@ -0,0 +1,27 @@
+-- Every writer announces its changes again, whatever its session settings. An instance
+-- caching statements can't otherwise tell that psql, an ops job or an instance with the
+-- cache disabled changed an account, and would keep serving the old statement.
+
+DROP TRIGGER IF EXISTS users_changed ON users;
+CREATE TRIGGER users_changed AFTER INSERT OR UPDATE OR DELETE ON users
+FOR EACH ROW EXECUTE FUNCTION notify_user_changed();
+
+DROP TRIGGER IF EXISTS users_truncated ON users;
+CREATE TRIGGER users_truncated AFTER TRUNCATE ON users
+FOR EACH STATEMENT EXECUTE FUNCTION notify_user_changed();
+
+-- Writers that don't bump the version, like a manual UPDATE, still get a new one, so their
+-- notification is newer than any cached statement of the account
+CREATE OR REPLACE FUNCTION bump_user_version() RETURNS TRIGGER
+LANGUAGE plpgsql AS $$
+BEGIN
+  IF NEW.version <= OLD.version THEN
+    NEW.version := OLD.version + 1;
+  END IF;
+  RETURN NEW;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS users_version ON users;
+CREATE TRIGGER users_version BEFORE UPDATE ON users
+FOR EACH ROW EXECUTE FUNCTION bump_user_version();
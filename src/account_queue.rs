@@ -0,0 +1,122 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex, time::Duration};
+
+use sqlx::{Pool, Postgres};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    db::{self, UpdateUserResult},
+    logging,
+    transaction::Transaction,
+};
+
+// An account's task stops after this long without transactions
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct QueuedTransaction {
+    transaction: Transaction,
+    reply: oneshot::Sender<UpdateUserResult>,
+}
+
+// One task per account applies that account's transactions in arrival order.
+// Whatever queued up while a database transaction was running is applied
+// together in the next one, so requests for a hot account don't all wait on
+// the row lock. The queues belong to a worker, other workers and instances
+// still take the row lock.
+pub struct AccountQueues {
+    pool: Arc<Pool<Postgres>>,
+    max_batch: usize,
+    queues: Mutex<HashMap<i32, mpsc::UnboundedSender<QueuedTransaction>>>,
+}
+
+impl AccountQueues {
+    pub fn new(pool: Arc<Pool<Postgres>>, max_batch: usize) -> AccountQueues {
+        return AccountQueues {
+            pool,
+            max_batch,
+            queues: Mutex::new(HashMap::new()),
+        };
+    }
+
+    fn sender(&self, id: i32) -> mpsc::UnboundedSender<QueuedTransaction> {
+        let mut queues = self.queues.lock().expect("account queues lock poisoned");
+        if let Some(sender) = queues.get(&id) {
+            if !sender.is_closed() {
+                return sender.clone();
+            }
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_account(self.pool.clone(), id, self.max_batch, receiver));
+        queues.insert(id, sender.clone());
+        return sender;
+    }
+
+    pub async fn apply(&self, id: i32, transaction: Transaction) -> UpdateUserResult {
+        let (reply, result) = oneshot::channel();
+        let mut queued = QueuedTransaction { transaction, reply };
+        // The account's task may have stopped for being idle since it was looked up
+        while let Err(mpsc::error::SendError(rejected)) = self.sender(id).send(queued) {
+            queued = rejected;
+        }
+        return result.await.unwrap_or_else(|_| {
+            return UpdateUserResult::InternalError(format!("Queue of user {} stopped", id));
+        });
+    }
+}
+
+async fn apply_batch(pool: &Pool<Postgres>, id: i32, batch: Vec<QueuedTransaction>) {
+    let (transactions, replies): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|queued| return (queued.transaction, queued.reply))
+        .unzip();
+    if transactions.len() > 1 {
+        logging::log!(
+            "Applying {} queued transactions of user {}",
+            transactions.len(),
+            id
+        );
+    }
+    let results = db::update_user_with_transactions(pool, id, &transactions).await;
+    // A request may have gone away, its transaction stays applied
+    replies
+        .into_iter()
+        .zip(results)
+        .for_each(|(reply, result)| {
+            let _ = reply.send(result);
+        });
+}
+
+async fn run_account(
+    pool: Arc<Pool<Postgres>>,
+    id: i32,
+    max_batch: usize,
+    mut receiver: mpsc::UnboundedReceiver<QueuedTransaction>,
+) {
+    loop {
+        let first = match tokio::time::timeout(IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Some(queued)) => queued,
+            Ok(None) => break,
+            Err(_) => {
+                // New senders see the queue closed and start another task, what
+                // was sent before that is still applied here
+                receiver.close();
+                let mut batch = Vec::new();
+                while let Ok(queued) = receiver.try_recv() {
+                    batch.push(queued);
+                }
+                if !batch.is_empty() {
+                    apply_batch(&pool, id, batch).await;
+                }
+                break;
+            }
+        };
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match receiver.try_recv() {
+                Ok(queued) => batch.push(queued),
+                Err(_) => break,
+            }
+        }
+        apply_batch(&pool, id, batch).await;
+    }
+    logging::log!("Queue of user {} stopped", id);
+}
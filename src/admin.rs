@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    auth, db, logging, request,
+    responses::{ApiError, ResponseType},
+    state::AppState,
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LimitRequest {
+    limite: i32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LogRequest {
+    enabled: Option<bool>,
+    errors_enabled: Option<bool>,
+}
+
+// Routes served by listeners with the admin route set. Everything but the
+// health check needs the admin token or an admin api key.
+pub async fn handle_request(state: &Arc<AppState>, request: &[u8]) -> ResponseType {
+    let (method, path) = match request::method_and_path(request) {
+        Some(method_and_path) => method_and_path,
+        None => return ResponseType::NotFound,
+    };
+    match (method, path) {
+        (b"GET", b"/health") => return health(state).await,
+        (_, b"/health") => return ResponseType::MethodNotAllowed,
+        _ => {}
+    };
+
+    if let Some(response) = auth::authorize_admin(
+        &state.pool,
+        &state.api_keys,
+        &state.circuit_breaker,
+        state.config.admin.token.as_deref(),
+        request,
+    )
+    .await
+    {
+        return response;
+    }
+
+    return match (method, path) {
+        (b"GET", b"/metrics") => metrics(state),
+        (b"GET", b"/admin/config") => dump_config(state),
+        (b"POST", b"/admin/reset") => reset(state).await,
+        (b"POST", b"/admin/seed") => seed(state).await,
+        (b"POST", b"/admin/cache/flush") => flush_caches(state),
+        (b"PUT", b"/admin/log") => set_log(request::body(request)),
+        (b"PUT", path) if path.starts_with(b"/admin/clientes/") && path.ends_with(b"/limite") => {
+            set_limit(state, request).await
+        }
+        (
+            _,
+            b"/metrics"
+            | b"/admin/config"
+            | b"/admin/reset"
+            | b"/admin/seed"
+            | b"/admin/cache/flush"
+            | b"/admin/log",
+        ) => ResponseType::MethodNotAllowed,
+        _ => ResponseType::NotFound,
+    };
+}
+
+async fn health(state: &Arc<AppState>) -> ResponseType {
+    return match db::ping(&state.pool).await {
+        Ok(()) => ResponseType::Ok("{\"status\":\"ok\"}".to_string()),
+        Err(e) => ResponseType::InternalServerError(e),
+    };
+}
+
+fn metrics(state: &Arc<AppState>) -> ResponseType {
+    return match state.metrics.to_json() {
+        Ok(metrics) => ResponseType::Ok(metrics),
+        Err(e) => ResponseType::InternalServerError(format!("Error serializing metrics: {}", e)),
+    };
+}
+
+fn dump_config(state: &Arc<AppState>) -> ResponseType {
+    return match serde_json::to_string(state.config.as_ref()) {
+        Ok(config) => ResponseType::Ok(config),
+        Err(e) => ResponseType::InternalServerError(format!("Error serializing config: {}", e)),
+    };
+}
+
+async fn reset(state: &Arc<AppState>) -> ResponseType {
+    logging::log!("Resetting db from admin");
+    return match db::reset(&state.pool).await {
+        Ok(()) => ResponseType::Ok(json!({"reset": true}).to_string()),
+        Err(e) => ResponseType::InternalServerError(e),
+    };
+}
+
+async fn seed(state: &Arc<AppState>) -> ResponseType {
+    return match db::seed(&state.pool).await {
+        Ok(created) => ResponseType::Ok(json!({ "created": created }).to_string()),
+        Err(e) => ResponseType::InternalServerError(e),
+    };
+}
+
+// Only this process' caches are flushed, other instances need their own call
+fn flush_caches(state: &Arc<AppState>) -> ResponseType {
+    state.api_keys.clear();
+    state.rate_limiter.clear();
+    state.statements.clear();
+    return ResponseType::Ok(
+        json!({"flushed": ["api_keys", "rate_limits", "statements"]}).to_string(),
+    );
+}
+
+fn set_log(body: &[u8]) -> ResponseType {
+    let log_request = match serde_json::from_slice::<LogRequest>(body) {
+        Ok(log_request) => log_request,
+        Err(e) => return ResponseType::from_json_error(e),
+    };
+    if let Some(enabled) = log_request.enabled {
+        logging::set_enable_log(enabled);
+    }
+    if let Some(errors_enabled) = log_request.errors_enabled {
+        logging::set_enable_log_error(errors_enabled);
+    }
+    let log = json!({
+        "enabled": logging::get_enable_log(),
+        "errors_enabled": logging::get_enable_log_error(),
+    });
+    return ResponseType::Ok(log.to_string());
+}
+
+async fn set_limit(state: &Arc<AppState>, request: &[u8]) -> ResponseType {
+    let id = match request::client_id(request) {
+        Some(id) => id,
+        None => return ResponseType::NotFound,
+    };
+    let limit_request = match serde_json::from_slice::<LimitRequest>(request::body(request)) {
+        Ok(limit_request) if limit_request.limite >= 0 => limit_request,
+        Ok(_) => {
+            return ResponseType::UnprocessableEntity(
+                ApiError::new("invalid_limit", "limite must not be negative").field("limite"),
+            );
+        }
+        Err(e) => return ResponseType::from_json_error(e),
+    };
+    return match db::update_limit(&state.pool, id, limit_request.limite).await {
+        db::UpdateLimitResult::Ok(balance) => ResponseType::Ok(
+            json!({ "limite": limit_request.limite, "saldo": balance }).to_string(),
+        ),
+        db::UpdateLimitResult::NotFound => ResponseType::NotFound,
+        db::UpdateLimitResult::Unprocessable(error) => ResponseType::UnprocessableEntity(error),
+        db::UpdateLimitResult::InternalError(e) => ResponseType::InternalServerError(e),
+    };
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+
+use crate::{circuit_breaker::CircuitBreaker, db, logging, request, responses::ResponseType};
+
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub client_ids: Vec<i32>,
+    pub is_admin: bool,
+}
+
+impl ApiKey {
+    pub fn can_access(&self, client_id: Option<i32>) -> bool {
+        if self.is_admin {
+            return true;
+        }
+        return match client_id {
+            Some(id) => self.client_ids.contains(&id),
+            // Requests without a client id are rejected later by the handlers
+            None => true,
+        };
+    }
+}
+
+type CachedApiKey = (ApiKey, Instant);
+
+// Keeps recently used keys in memory so most requests skip the api_keys lookup.
+// Revoked keys stay valid for at most `ttl`. Unknown keys aren't kept, so
+// random ones can't grow the map.
+pub struct ApiKeyCache {
+    ttl: Duration,
+    keys: Mutex<HashMap<Vec<u8>, CachedApiKey>>,
+}
+
+impl ApiKeyCache {
+    pub fn new(ttl: Duration) -> Self {
+        return ApiKeyCache {
+            ttl,
+            keys: Mutex::new(HashMap::new()),
+        };
+    }
+
+    pub fn clear(&self) {
+        self.keys.lock().expect("api key cache poisoned").clear();
+    }
+
+    fn get(&self, key_hash: &[u8]) -> Option<ApiKey> {
+        let keys = self.keys.lock().expect("api key cache poisoned");
+        return match keys.get(key_hash) {
+            Some((key, cached_at)) if cached_at.elapsed() < self.ttl => Some(key.clone()),
+            _ => None,
+        };
+    }
+
+    fn insert(&self, key_hash: Vec<u8>, key: ApiKey) {
+        let mut keys = self.keys.lock().expect("api key cache poisoned");
+        keys.retain(|_, (_, cached_at)| return cached_at.elapsed() < self.ttl);
+        keys.insert(key_hash, (key, Instant::now()));
+    }
+}
+
+pub fn hash_key(raw_key: &[u8]) -> Vec<u8> {
+    return Sha256::digest(raw_key).to_vec();
+}
+
+fn get_raw_key(request: &[u8]) -> Option<&[u8]> {
+    if let Some(authorization) = request::header(request, "Authorization") {
+        return authorization.strip_prefix(b"Bearer ").map(request::trim);
+    }
+    return request::header(request, "X-Api-Key");
+}
+
+async fn authenticate(
+    pool: &Pool<Postgres>,
+    cache: &ApiKeyCache,
+    breaker: &CircuitBreaker,
+    raw_key: &[u8],
+) -> Result<ApiKey, ResponseType> {
+    let key_hash = hash_key(raw_key);
+    if let Some(api_key) = cache.get(&key_hash) {
+        return Ok(api_key);
+    }
+    let read_result = match breaker.call(db::read_api_key(pool, &key_hash)).await {
+        Ok(read_result) => read_result,
+        Err(retry_after) => return Err(ResponseType::ServiceUnavailable(retry_after)),
+    };
+    return match read_result {
+        db::ReadApiKeyResult::Ok(api_key) => {
+            cache.insert(key_hash, api_key.clone());
+            Ok(api_key)
+        }
+        db::ReadApiKeyResult::NotFound => {
+            logging::log!("Unknown api key");
+            return Err(ResponseType::Unauthorized);
+        }
+        db::ReadApiKeyResult::InternalError(e) => Err(ResponseType::InternalServerError(e)),
+    };
+}
+
+// Returns None when the request may proceed, or the response to send otherwise
+pub async fn authorize(
+    pool: &Pool<Postgres>,
+    cache: &ApiKeyCache,
+    breaker: &CircuitBreaker,
+    request: &[u8],
+) -> Option<ResponseType> {
+    let raw_key = match get_raw_key(request) {
+        Some(raw_key) if !raw_key.is_empty() => raw_key,
+        _ => {
+            logging::log!("Missing api key");
+            return Some(ResponseType::Unauthorized);
+        }
+    };
+    let api_key = match authenticate(pool, cache, breaker, raw_key).await {
+        Ok(api_key) => api_key,
+        Err(response) => return Some(response),
+    };
+
+    let client_id = request::client_id(request);
+    if !api_key.can_access(client_id) {
+        logging::log!("Api key not allowed for client {:?}", client_id);
+        return Some(ResponseType::Forbidden);
+    }
+    return None;
+}
+
+// Admin routes accept the configured admin token or an admin api key
+pub async fn authorize_admin(
+    pool: &Pool<Postgres>,
+    cache: &ApiKeyCache,
+    breaker: &CircuitBreaker,
+    admin_token: Option<&str>,
+    request: &[u8],
+) -> Option<ResponseType> {
+    let raw_key = match get_raw_key(request) {
+        Some(raw_key) if !raw_key.is_empty() => raw_key,
+        _ => return Some(ResponseType::Unauthorized),
+    };
+    if let Some(admin_token) = admin_token {
+        // Comparing digests keeps the comparison time independent of the token
+        if hash_key(raw_key) == hash_key(admin_token.as_bytes()) {
+            return None;
+        }
+    }
+    return match authenticate(pool, cache, breaker, raw_key).await {
+        Ok(api_key) if api_key.is_admin => None,
+        Ok(_) => Some(ResponseType::Forbidden),
+        Err(response) => Some(response),
+    };
+}
@@ -1,13 +1,19 @@
-use std::sync::Arc;
-
-use crate::{db, responses::ResponseType, transaction::Transaction, user::User};
+use crate::{
+    clock, db, request,
+    responses::{ApiError, ResponseType},
+    state::AppState,
+    statement_cache::CachedStatement,
+    transaction::Transaction,
+    user::User,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct StatementResponseSaldo {
     total: i32,
-    data_extrato: String,
+    #[serde(with = "clock::timestamp")]
+    data_extrato: DateTime<Utc>,
     limite: i32,
 }
 
@@ -18,21 +24,26 @@ pub struct StatementResponse<'a> {
     ultimas_transacoes: [Option<&'a Transaction>; 10],
 }
 
-pub async fn get(
-    pool: Arc<Pool<Postgres>>,
-    request: &mut [u8; 512],
-    request_size: usize,
-) -> ResponseType {
+pub async fn get(state: &AppState, request: &mut [u8; 512], request_size: usize) -> ResponseType {
     if request_size < 15 {
-        return ResponseType::UnprocessableEntity;
+        return ResponseType::BadRequest(ApiError::new(
+            "invalid_request",
+            "Request is too short to hold a statement path",
+        ));
     }
-    let id = match get_id(request) {
+    let id = match request::client_id(&request[..request_size]) {
         Some(id) => id,
         None => {
-            return ResponseType::NotFound;
+            return ResponseType::BadRequest(
+                ApiError::new("invalid_id", "Client id must be a number").field("id"),
+            );
         }
     };
 
+    if let Some(cached) = state.statements.get(id) {
+        return render(&cached, state.clock.now());
+    }
+
     let mut user = User {
         id,
         balance_limit: 0,
@@ -40,8 +51,19 @@ pub async fn get(
         transactions_count: 0,
         last_transaction: 0,
         transactions: Default::default(),
+        version: 0,
+    };
+    let read = async {
+        return match &state.replica {
+            Some(replica) => replica.read_user(state.pool.clone(), id, &mut user).await,
+            None => db::read_user(state.pool.clone(), id, &mut user).await,
+        };
+    };
+    let read_result = match state.circuit_breaker.call(read).await {
+        Ok(read_result) => read_result,
+        Err(retry_after) => return ResponseType::ServiceUnavailable(retry_after),
     };
-    match db::read_user(pool, id, &mut user).await {
+    match read_result {
         db::ReadUserResult::Ok => {}
         db::ReadUserResult::NotFound => {
             return ResponseType::NotFound;
@@ -51,20 +73,9 @@ pub async fn get(
         }
     };
 
-    let current_datetime = chrono::Local::now();
-    let formatted_datetime = current_datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-    let mut ordered_transactions: [Option<&Transaction>; 10] = [None; 10];
-    user.get_ordered_transactions(&mut ordered_transactions);
-
-    let statement_response = StatementResponse {
-        saldo: StatementResponseSaldo {
-            total: user.balance,
-            data_extrato: formatted_datetime,
-            limite: user.balance_limit,
-        },
-        ultimas_transacoes: ordered_transactions,
-    };
+    state.statements.store(&user);
 
+    let statement_response = statement(&user, state.clock.now());
     let serialize_result = serde_json::to_string(&statement_response);
     return match serialize_result {
         Ok(response_body) => ResponseType::Ok(response_body),
@@ -75,16 +86,58 @@ pub async fn get(
     };
 }
 
-fn get_id(request: &[u8]) -> Option<i32> {
-    let first_separator = request[13];
-    let maybe_id = request[14];
-    let second_separator = request[15];
-    if first_separator != b'/' || second_separator != b'/' || !maybe_id.is_ascii_digit() {
-        return None;
+// Same body as the StatementResponse serialization, from the cached parts
+fn render(cached: &CachedStatement, now: DateTime<Utc>) -> ResponseType {
+    let saldo = StatementResponseSaldo {
+        total: cached.balance,
+        data_extrato: now,
+        limite: cached.balance_limit,
+    };
+    return match serde_json::to_string(&saldo) {
+        Ok(saldo) => ResponseType::Ok(format!(
+            "{{\"saldo\":{},\"ultimas_transacoes\":{}}}",
+            saldo, cached.transactions
+        )),
+        Err(e) => {
+            let error_string = format!("Error serializing response: {}", e);
+            return ResponseType::InternalServerError(error_string);
+        }
+    };
+}
+
+pub fn cached_statement(user: &User) -> Result<CachedStatement, String> {
+    let statement = statement(user, DateTime::<Utc>::default());
+    let transactions = serde_json::to_string(&TransactionsJson(&statement.ultimas_transacoes))
+        .map_err(|e| return format!("Error serializing transactions: {}", e))?;
+    return Ok(CachedStatement {
+        balance: user.balance,
+        balance_limit: user.balance_limit,
+        transactions,
+    });
+}
+
+pub fn statement(user: &User, now: DateTime<Utc>) -> StatementResponse<'_> {
+    let mut ordered_transactions: [Option<&Transaction>; 10] = [None; 10];
+    user.get_ordered_transactions(&mut ordered_transactions);
+    return StatementResponse {
+        saldo: StatementResponseSaldo {
+            total: user.balance,
+            data_extrato: now,
+            limite: user.balance_limit,
+        },
+        ultimas_transacoes: ordered_transactions,
+    };
+}
+
+struct TransactionsJson<'a, 'b>(&'b [Option<&'a Transaction>; 10]);
+
+impl Serialize for TransactionsJson<'_, '_> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        return serialize_transactions(self.0, s);
     }
-    let id = i32::from(maybe_id);
-    let zero_ascii = i32::from(b'0');
-    return Some(id - zero_ascii);
 }
 
 fn serialize_transactions<S>(v: &[Option<&Transaction>; 10], s: S) -> Result<S::Ok, S::Error>
@@ -94,3 +147,71 @@ where
     let i = v.iter().position(|x| return x.is_none()).unwrap_or(v.len());
     return v[0..i].serialize(s);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, FixedClock},
+        user::UserDb,
+    };
+
+    fn clock() -> FixedClock {
+        let now =
+            clock::timestamp::parse("2024-02-03T04:05:06.123456789Z").expect("Invalid timestamp");
+        return FixedClock(now);
+    }
+
+    fn user() -> User {
+        let mut user = User::try_from_db(UserDb {
+            id: 1,
+            balance_limit: 1000,
+            balance: 0,
+            transactions_count: 0,
+            last_transaction: 0,
+            encoded_transactions: None,
+            version: 0,
+        })
+        .expect("Invalid user");
+        [
+            ("c", 100, "2024-02-03T04:05:04.000001Z"),
+            ("d", 30, "2024-02-03T04:05:05.5Z"),
+        ]
+        .iter()
+        .for_each(|&(tipo, valor, realizada_em)| {
+            let transaction = Transaction {
+                valor,
+                descricao: tipo.to_string(),
+                tipo: tipo.to_string(),
+                realizada_em: clock::timestamp::parse(realizada_em).expect("Invalid timestamp"),
+            };
+            user.compute_transaction(&transaction);
+            user.add_transaction(&transaction);
+        });
+        return user;
+    }
+
+    const STATEMENT: &str = concat!(
+        r#"{"saldo":{"total":70,"data_extrato":"2024-02-03T04:05:06.123456Z","limite":1000},"#,
+        r#""ultimas_transacoes":["#,
+        r#"{"valor":30,"descricao":"d","tipo":"d","realizada_em":"2024-02-03T04:05:05.500000Z"},"#,
+        r#"{"valor":100,"descricao":"c","tipo":"c","realizada_em":"2024-02-03T04:05:04.000001Z"}]}"#
+    );
+
+    #[test]
+    fn statement_has_microsecond_utc_timestamps() {
+        let user = user();
+        let body = serde_json::to_string(&statement(&user, clock().now()))
+            .expect("Failed to serialize statement");
+        assert_eq!(body, STATEMENT);
+    }
+
+    #[test]
+    fn cached_statement_renders_the_same_body() {
+        let cached = cached_statement(&user()).expect("Failed to cache statement");
+        match render(&cached, clock().now()) {
+            ResponseType::Ok(body) => assert_eq!(body, STATEMENT),
+            response => panic!("Unexpected response {}", response.status_code()),
+        };
+    }
+}
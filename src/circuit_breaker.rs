@@ -0,0 +1,238 @@
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::CircuitBreakerConfig, db::Outcome, logging};
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // One call is probing the database, the others still fail fast. A probe
+    // that never reports, because its request panicked or went away, is
+    // replaced by the first call after `until`.
+    HalfOpen { until: Instant },
+}
+
+// Stops sending queries to a failing database. After failure_threshold
+// consecutive failed calls every call is refused for open_ms, then a single
+// call decides whether to close the circuit or keep it open.
+pub struct CircuitBreaker {
+    enabled: bool,
+    failure_threshold: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> CircuitBreaker {
+        return CircuitBreaker {
+            enabled: config.enabled,
+            failure_threshold: config.failure_threshold,
+            open_for: Duration::from_millis(config.open_ms),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        };
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        return self.state.lock().expect("circuit breaker lock poisoned");
+    }
+
+    // Runs a database call unless the circuit is open and counts its outcome.
+    // Err(u64) is the Retry-After in seconds of a call that wasn't made.
+    pub async fn call<T: Outcome>(&self, query: impl Future<Output = T>) -> Result<T, u64> {
+        self.allow()?;
+        let result = query.await;
+        self.record(!result.failed());
+        return Ok(result);
+    }
+
+    fn allow(&self) -> Result<(), u64> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut state = self.state();
+        let now = Instant::now();
+        return match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } | State::HalfOpen { until } if now < until => {
+                Err((until - now).as_secs().max(1))
+            }
+            State::Open { .. } => {
+                logging::log!("Circuit half open, probing the database");
+                *state = State::HalfOpen {
+                    until: now + self.open_for,
+                };
+                Ok(())
+            }
+            State::HalfOpen { .. } => {
+                logging::error!("Circuit probe didn't report, probing the database again");
+                *state = State::HalfOpen {
+                    until: now + self.open_for,
+                };
+                Ok(())
+            }
+        };
+    }
+
+    fn record(&self, success: bool) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state();
+        if success {
+            if !matches!(*state, State::Closed { .. }) {
+                logging::error!("Circuit closed, the database is answering again");
+            }
+            *state = State::Closed { failures: 0 };
+            return;
+        }
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            // The probe failed
+            State::HalfOpen { .. } => self.failure_threshold,
+            // A call let through before the circuit opened
+            State::Open { .. } => return,
+        };
+        if failures < self.failure_threshold {
+            *state = State::Closed { failures };
+            return;
+        }
+        logging::error!(
+            "Circuit open for {:?} after {} consecutive failures",
+            self.open_for,
+            failures
+        );
+        *state = State::Open {
+            until: Instant::now() + self.open_for,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+
+    const OPEN_FOR: Duration = Duration::from_millis(50);
+
+    // A database call that succeeds or fails without a database
+    struct Stub(bool);
+
+    impl Outcome for Stub {
+        fn failed(&self) -> bool {
+            return !self.0;
+        }
+    }
+
+    fn breaker(enabled: bool) -> CircuitBreaker {
+        return CircuitBreaker::new(&CircuitBreakerConfig {
+            enabled,
+            failure_threshold: 3,
+            open_ms: u64::try_from(OPEN_FOR.as_millis()).expect("open_ms overflows"),
+        });
+    }
+
+    async fn call(breaker: &CircuitBreaker, success: bool) -> Result<bool, u64> {
+        return breaker
+            .call(async { return Stub(success) })
+            .await
+            .map(|stub| return stub.0);
+    }
+
+    fn open_until(breaker: &CircuitBreaker) -> Option<Instant> {
+        return match *breaker.state() {
+            State::Open { until } => Some(until),
+            _ => None,
+        };
+    }
+
+    async fn open(breaker: &CircuitBreaker) {
+        for _ in 0..3 {
+            assert_eq!(call(breaker, false).await, Ok(false));
+        }
+        assert_eq!(open_until(breaker).is_some(), breaker.enabled);
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        let breaker = breaker(true);
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        // A success in between starts the count over
+        assert_eq!(call(&breaker, true).await, Ok(true));
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        assert!(matches!(*breaker.state(), State::Closed { failures: 2 }));
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        assert!(matches!(*breaker.state(), State::Open { .. }));
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_fast() {
+        let breaker = breaker(true);
+        open(&breaker).await;
+        let ran = AtomicBool::new(false);
+        let result = breaker
+            .call(async {
+                ran.store(true, Ordering::Relaxed);
+                return Stub(true);
+            })
+            .await;
+        assert!(matches!(result, Err(1)));
+        assert!(!ran.load(Ordering::Relaxed));
+        // Late failures of calls let through before it opened don't extend it
+        let Some(until) = open_until(&breaker) else {
+            panic!("Circuit isn't open");
+        };
+        breaker.record(false);
+        assert_eq!(open_until(&breaker), Some(until));
+    }
+
+    #[tokio::test]
+    async fn successful_probe_closes_the_circuit() {
+        let breaker = breaker(true);
+        open(&breaker).await;
+        tokio::time::sleep(OPEN_FOR).await;
+        assert_eq!(breaker.allow(), Ok(()));
+        assert!(matches!(*breaker.state(), State::HalfOpen { .. }));
+        // Only the probe goes through
+        assert_eq!(call(&breaker, true).await, Err(1));
+        breaker.record(true);
+        assert!(matches!(*breaker.state(), State::Closed { failures: 0 }));
+        assert_eq!(call(&breaker, true).await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn failed_probe_reopens_the_circuit() {
+        let breaker = breaker(true);
+        open(&breaker).await;
+        tokio::time::sleep(OPEN_FOR).await;
+        assert_eq!(call(&breaker, false).await, Ok(false));
+        assert!(matches!(*breaker.state(), State::Open { .. }));
+        assert_eq!(call(&breaker, true).await, Err(1));
+    }
+
+    #[tokio::test]
+    async fn stale_probe_is_replaced() {
+        let breaker = breaker(true);
+        open(&breaker).await;
+        tokio::time::sleep(OPEN_FOR).await;
+        // The probe never reports
+        assert_eq!(breaker.allow(), Ok(()));
+        assert_eq!(breaker.allow(), Err(1));
+        tokio::time::sleep(OPEN_FOR).await;
+        assert_eq!(call(&breaker, true).await, Ok(true));
+        assert!(matches!(*breaker.state(), State::Closed { failures: 0 }));
+    }
+
+    #[tokio::test]
+    async fn disabled_breaker_never_opens() {
+        let breaker = breaker(false);
+        open(&breaker).await;
+        open(&breaker).await;
+        assert_eq!(call(&breaker, true).await, Ok(true));
+    }
+}
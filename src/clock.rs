@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::ClockConfig;
+
+// Source of the current time for handlers, so it can be frozen
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        return Utc::now();
+    }
+}
+
+// Always returns the same instant
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        return self.0;
+    }
+}
+
+pub fn from_config(config: &ClockConfig) -> Arc<dyn Clock> {
+    return match config.fixed_at {
+        Some(fixed_at) => Arc::new(FixedClock(fixed_at)),
+        None => Arc::new(SystemClock),
+    };
+}
+
+// Serde format of timestamps, RFC 3339 in UTC with microseconds
+pub mod timestamp {
+    use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn format(timestamp: &DateTime<Utc>) -> String {
+        return timestamp.to_rfc3339_opts(SecondsFormat::Micros, true);
+    }
+
+    pub fn parse(timestamp: &str) -> Result<DateTime<Utc>, String> {
+        // Empty slots of the stored transactions array
+        if timestamp.is_empty() {
+            return Ok(DateTime::<Utc>::default());
+        }
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) {
+            return Ok(timestamp.with_timezone(&Utc));
+        }
+        // Transactions stored before timestamps were UTC used the server's local time
+        let naive = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S")
+            .map_err(|e| return format!("invalid timestamp {:?}: {}", timestamp, e))?;
+        return Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|timestamp| return timestamp.with_timezone(&Utc))
+            .ok_or_else(|| return format!("invalid local timestamp {:?}", timestamp));
+    }
+
+    pub fn serialize<S>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        return serializer.serialize_str(&format(timestamp));
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let timestamp = String::deserialize(deserializer)?;
+        return parse(&timestamp).map_err(D::Error::custom);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, NaiveDate, TimeZone};
+
+    use super::*;
+
+    #[test]
+    fn fixed_clock_formats_microseconds_in_utc() {
+        let fixed_at = DateTime::parse_from_rfc3339("2024-02-03T01:05:06.123456789-03:00")
+            .expect("Invalid timestamp")
+            .with_timezone(&Utc);
+        let clock = FixedClock(fixed_at);
+        assert_eq!(clock.now(), fixed_at);
+        assert_eq!(
+            timestamp::format(&clock.now()),
+            "2024-02-03T04:05:06.123456Z"
+        );
+        let whole_second = timestamp::parse("2024-02-03T04:05:06Z").expect("Invalid timestamp");
+        assert_eq!(
+            timestamp::format(&whole_second),
+            "2024-02-03T04:05:06.000000Z"
+        );
+    }
+
+    #[test]
+    fn parses_legacy_local_timestamps() {
+        let naive = NaiveDate::from_ymd_opt(2024, 2, 3)
+            .and_then(|date| return date.and_hms_opt(4, 5, 6))
+            .expect("Invalid date");
+        // Whatever the time zone the tests run in
+        let expected = Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .expect("Invalid local time")
+            .with_timezone(&Utc);
+        assert_eq!(timestamp::parse("2024-02-03 04:05:06"), Ok(expected));
+        assert!(timestamp::parse("2024-02-03 04:05").is_err());
+        assert!(timestamp::parse("03/02/2024 04:05:06").is_err());
+    }
+
+    #[test]
+    fn empty_timestamps_are_unwritten_slots() {
+        assert_eq!(timestamp::parse(""), Ok(DateTime::<Utc>::default()));
+    }
+}
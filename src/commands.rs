@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tokio::task::JoinSet;
+
+use crate::{
+    account_queue::AccountQueues,
+    bank_statement::{self, StatementResponse},
+    clock,
+    config::{Config, StorageConfig, UpdateStrategy},
+    db::{self, UpdateUserResult},
+    group_commit::GroupCommit,
+    logging, migrations,
+    transaction::Transaction,
+    user::User,
+    verify, worker,
+};
+
+// Exit codes of every command, also listed in --help
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_INVALID_CONFIG: i32 = 2;
+pub const EXIT_INCONSISTENT: i32 = 3;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SeedAccount {
+    id: i32,
+    limite: i32,
+    #[serde(default)]
+    saldo: i32,
+}
+
+#[derive(Serialize)]
+struct ExportedStatement<'a> {
+    id: i32,
+    #[serde(flatten)]
+    statement: StatementResponse<'a>,
+}
+
+fn exit_code(action: &str, result: Result<i32, String>) -> i32 {
+    return match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Failed to {action}: {e}");
+            return EXIT_FAILURE;
+        }
+    };
+}
+
+// Maintenance commands need the schema this binary was built for
+async fn connect(config: &Config) -> Result<Pool<Postgres>, String> {
+    let pool = db::connect(config, 1).await?;
+    if let Some(version) = migrations::pending(&pool).await?.first() {
+        return Err(format!(
+            "Migration {} is pending, run the migrate command first",
+            migrations::describe(*version)
+        ));
+    }
+    return Ok(pool);
+}
+
+pub fn migrate(config: &Config) -> i32 {
+    let result = worker::new_runtime().block_on(async {
+        let pool = db::connect(config, 1).await?;
+        let applied = migrations::run(&pool).await?;
+        if applied.is_empty() {
+            println!("Database is up to date");
+        }
+        for version in applied {
+            println!("Applied migration {}", migrations::describe(version));
+        }
+        return Ok(EXIT_SUCCESS);
+    });
+    return exit_code("migrate database", result);
+}
+
+pub fn reset(config: &Config) -> i32 {
+    let result = worker::new_runtime().block_on(async {
+        let pool = connect(config).await?;
+        db::reset(&pool).await?;
+        println!("Database reset to the initial accounts");
+        return Ok(EXIT_SUCCESS);
+    });
+    return exit_code("reset database", result);
+}
+
+fn read_seed_file(path: &Path) -> Result<Vec<User>, String> {
+    let file =
+        File::open(path).map_err(|e| return format!("failed to open {}: {}", path.display(), e))?;
+    let accounts: Vec<SeedAccount> = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| return format!("failed to parse {}: {}", path.display(), e))?;
+    let mut users = Vec::with_capacity(accounts.len());
+    for account in accounts {
+        if account.limite < 0 || account.saldo < -account.limite {
+            return Err(format!(
+                "account {} has limite {} and saldo {}, saldo can't be below -limite",
+                account.id, account.limite, account.saldo
+            ));
+        }
+        users.push(User {
+            id: account.id,
+            balance_limit: account.limite,
+            balance: account.saldo,
+            transactions_count: 0,
+            last_transaction: 0,
+            transactions: Default::default(),
+            version: 0,
+        });
+    }
+    return Ok(users);
+}
+
+// Without a file the initial accounts are created
+pub fn seed(config: &Config, file: Option<&Path>) -> i32 {
+    let users = match file.map(read_seed_file).transpose() {
+        Ok(users) => users,
+        Err(e) => {
+            eprintln!("Invalid seed file: {e}");
+            return EXIT_INVALID_CONFIG;
+        }
+    };
+    let result = worker::new_runtime().block_on(async {
+        let pool = connect(config).await?;
+        let created = match users {
+            Some(users) => db::seed_users(&pool, users).await?,
+            None => db::seed(&pool).await?,
+        };
+        println!("Created {created} accounts");
+        return Ok(EXIT_SUCCESS);
+    });
+    return exit_code("seed database", result);
+}
+
+// Scans every account, with repair the fixable violations are written back
+pub fn verify(config: &Config, repair: bool) -> i32 {
+    let result = worker::new_runtime().block_on(async {
+        let pool = connect(config).await?;
+        let with_ledger = db::has_ledger(&pool).await?;
+        let ledger_balances = if with_ledger {
+            db::ledger_balances(&pool).await?
+        } else {
+            println!("No ledger table, skipping the ledger checks");
+            HashMap::new()
+        };
+        let ledger_balance = |id: i32| {
+            return with_ledger.then(|| return ledger_balances.get(&id).copied().unwrap_or(0));
+        };
+
+        let users = db::read_user_rows(&pool).await?;
+        let mut remaining = 0;
+        let mut repaired = 0;
+        for user in &users {
+            let violations = verify::check_user(user, ledger_balance(user.id));
+            for violation in &violations {
+                println!(
+                    "account {}: {} ({})",
+                    violation.id, violation.message, violation.code
+                );
+            }
+            if violations.is_empty() {
+                continue;
+            }
+            if !repair {
+                remaining += violations.len();
+                continue;
+            }
+            // Checked again under the lock, the account may have moved since it was read
+            if db::repair_user(&pool, user.id, with_ledger, verify::repair_user).await? {
+                println!("account {}: repaired", user.id);
+                repaired += 1;
+            }
+        }
+        if repair {
+            for user in db::read_user_rows(&pool).await? {
+                remaining += verify::check_user(&user, ledger_balance(user.id)).len();
+            }
+        }
+
+        println!(
+            "Checked {} accounts, repaired {}, {} violations remaining",
+            users.len(),
+            repaired,
+            remaining
+        );
+        return Ok(if remaining == 0 {
+            EXIT_SUCCESS
+        } else {
+            EXIT_INCONSISTENT
+        });
+    });
+    return exit_code("verify database", result);
+}
+
+// Writes one JSON statement per line, in the same format as GET /clientes/{id}/extrato
+pub fn export(config: &Config, output: Option<&Path>, client: Option<i32>) -> i32 {
+    let result = worker::new_runtime().block_on(async {
+        let pool = connect(config).await?;
+        let users: Vec<User> = db::read_users(&pool)
+            .await?
+            .into_iter()
+            .filter(|user| return client.is_none() || client == Some(user.id))
+            .collect();
+        if let (Some(id), true) = (client, users.is_empty()) {
+            return Err(format!("Client {id} not found"));
+        }
+
+        let writer: Box<dyn Write> = match output {
+            Some(path) => Box::new(
+                File::create(path)
+                    .map_err(|e| return format!("failed to create {}: {}", path.display(), e))?,
+            ),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        let mut writer = BufWriter::new(writer);
+        let now = clock::from_config(&config.clock).now();
+        for user in &users {
+            let exported = ExportedStatement {
+                id: user.id,
+                statement: bank_statement::statement(user, now),
+            };
+            serde_json::to_writer(&mut writer, &exported)
+                .map_err(|e| return format!("Error serializing statement: {}", e))?;
+            writeln!(writer).map_err(|e| return format!("Error writing statement: {}", e))?;
+        }
+        writer
+            .flush()
+            .map_err(|e| return format!("Error writing statements: {}", e))?;
+        eprintln!("Exported {} statements", users.len());
+        return Ok(EXIT_SUCCESS);
+    });
+    return exit_code("export statements", result);
+}
+
+#[derive(Default)]
+struct BenchResult {
+    latencies: Vec<Duration>,
+    ok: usize,
+    rejected: usize,
+    conflicts: usize,
+    errors: usize,
+}
+
+async fn bench_worker(
+    pool: Arc<Pool<Postgres>>,
+    queues: Arc<AccountQueues>,
+    group: Arc<GroupCommit>,
+    storage: StorageConfig,
+    next: Arc<AtomicUsize>,
+    requests: usize,
+    accounts: i32,
+) -> BenchResult {
+    let mut result = BenchResult::default();
+    loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        if i >= requests {
+            break;
+        }
+        let transaction = Transaction {
+            valor: 1,
+            descricao: "bench".to_string(),
+            // Alternating keeps the balances near zero
+            tipo: match i % 2 {
+                0 => "c",
+                _ => "d",
+            }
+            .to_string(),
+            realizada_em: chrono::Utc::now(),
+        };
+        let id = -1 - i32::try_from(i).unwrap_or(0) % accounts;
+        let started_at = Instant::now();
+        match db::apply_transaction(pool.clone(), &queues, &group, &storage, id, &transaction).await
+        {
+            UpdateUserResult::Ok(_) => result.ok += 1,
+            UpdateUserResult::Unprocessable(_) | UpdateUserResult::NotFound => result.rejected += 1,
+            UpdateUserResult::Conflict => result.conflicts += 1,
+            UpdateUserResult::InternalError(e) => {
+                logging::error!("{}", e);
+                result.errors += 1;
+            }
+        };
+        result.latencies.push(started_at.elapsed());
+    }
+    return result;
+}
+
+fn percentile(sorted: &[Duration], percentile: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    return sorted[(sorted.len() - 1) * percentile / 100];
+}
+
+// Limit of the bench accounts, high enough that no debit is refused
+const BENCH_ACCOUNT_LIMIT: i32 = 1_000_000;
+
+// The bench runs on accounts of its own, with negative ids that requests can't
+// name, so the clients' balances and statements are left alone
+fn bench_account_ids(accounts: i32) -> Vec<i32> {
+    return (1..=accounts).map(|i| return -i).collect();
+}
+
+pub fn bench(config: &Config, requests: usize, concurrency: usize, accounts: i32) -> i32 {
+    if concurrency == 0 || accounts < 1 {
+        eprintln!("--concurrency and --accounts must be positive");
+        return EXIT_INVALID_CONFIG;
+    }
+    let result = worker::new_runtime().block_on(async {
+        let pool = connect(config).await?;
+        let ids = bench_account_ids(accounts);
+        // Left behind by an interrupted run
+        db::delete_users(&pool, &ids).await?;
+        let bench_accounts = ids
+            .iter()
+            .map(|&id| {
+                return User {
+                    id,
+                    balance_limit: BENCH_ACCOUNT_LIMIT,
+                    balance: 0,
+                    transactions_count: 0,
+                    last_transaction: 0,
+                    transactions: Default::default(),
+                    version: 0,
+                };
+            })
+            .collect();
+        db::seed_users(&pool, bench_accounts).await?;
+        let result = run_bench(config, requests, concurrency, accounts).await;
+        let deleted = db::delete_users(&pool, &ids).await;
+        pool.close().await;
+        deleted?;
+        return result;
+    });
+    return exit_code("run benchmark", result);
+}
+
+async fn run_bench(
+    config: &Config,
+    requests: usize,
+    concurrency: usize,
+    accounts: i32,
+) -> Result<i32, String> {
+    let max_connections = u32::try_from(concurrency).unwrap_or(u32::MAX);
+    let pool = Arc::new(db::connect(config, max_connections).await?);
+    let queues = Arc::new(AccountQueues::new(
+        pool.clone(),
+        config.storage.queue_max_batch,
+    ));
+    let group = Arc::new(GroupCommit::new(
+        pool.clone(),
+        Duration::from_millis(config.storage.group_window_ms),
+        config.storage.group_max_size,
+    ));
+    println!("{requests} transactions, {concurrency} in flight, over {accounts} bench accounts");
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10} {:>8} {:>9} {:>9} {:>7}",
+        "strategy", "tx/s", "p50", "p99", "max", "ok", "rejected", "conflicts", "errors"
+    );
+    for strategy in UpdateStrategy::value_variants() {
+        let storage = StorageConfig {
+            update_strategy: *strategy,
+            ..config.storage.clone()
+        };
+        let next = Arc::new(AtomicUsize::new(0));
+        let started_at = Instant::now();
+        let mut workers = JoinSet::new();
+        for _ in 0..concurrency {
+            workers.spawn(bench_worker(
+                pool.clone(),
+                queues.clone(),
+                group.clone(),
+                storage.clone(),
+                next.clone(),
+                requests,
+                accounts,
+            ));
+        }
+        let mut total = BenchResult::default();
+        while let Some(result) = workers.join_next().await {
+            let result = result.map_err(|e| return format!("Bench worker failed: {}", e))?;
+            total.latencies.extend(result.latencies);
+            total.ok += result.ok;
+            total.rejected += result.rejected;
+            total.conflicts += result.conflicts;
+            total.errors += result.errors;
+        }
+        let elapsed = started_at.elapsed();
+        total.latencies.sort();
+        println!(
+            "{:<12} {:>10.0} {:>10.2?} {:>10.2?} {:>10.2?} {:>8} {:>9} {:>9} {:>7}",
+            format!("{:?}", strategy).to_lowercase(),
+            requests as f64 / elapsed.as_secs_f64(),
+            percentile(&total.latencies, 50),
+            percentile(&total.latencies, 99),
+            total.latencies.last().copied().unwrap_or_default(),
+            total.ok,
+            total.rejected,
+            total.conflicts,
+            total.errors
+        );
+    }
+    return Ok(EXIT_SUCCESS);
+}
@@ -1,41 +1,220 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use sqlx::{Pool, Postgres};
+use sqlx::{
+    postgres::{PgConnectOptions, PgConnection, PgPoolOptions},
+    Pool, Postgres,
+};
 
+use crate::account_queue::AccountQueues;
+use crate::auth::ApiKey;
+use crate::clock;
+use crate::config::{Config, StorageConfig, UpdateStrategy};
+use crate::group_commit::GroupCommit;
 use crate::logging;
+use crate::responses::ApiError;
 use crate::transaction::{self, Transaction};
 use crate::user::{TransactionResult, User, UserDb};
 
+const MAX_CONNECT_RETRY_WAIT: Duration = Duration::from_secs(5);
+// Longest wait before the first optimistic retry, doubled after every conflict up to 64 times
+const OPTIMISTIC_RETRY_WAIT: Duration = Duration::from_millis(5);
+
+fn connect_options(config: &Config, url: &str) -> Result<PgConnectOptions, String> {
+    let options = PgConnectOptions::from_str(url)
+        .map_err(|e| return format!("Invalid database url: {}", e))?;
+    return Ok(options.options([("statement_timeout", config.database.statement_timeout_ms)]));
+}
+
+pub async fn connect(config: &Config, max_connections: u32) -> Result<Pool<Postgres>, String> {
+    return PgPoolOptions::new()
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_millis(config.database.acquire_timeout_ms))
+        .connect_with(connect_options(config, config.database_url())?)
+        .await
+        .map_err(|e| return format!("Failed to connect to database: {}", e));
+}
+
+// For startup, when the database may still be coming up
+pub async fn connect_with_retries(
+    config: &Config,
+    max_connections: u32,
+) -> Result<Pool<Postgres>, String> {
+    let mut wait = Duration::from_millis(config.database.connect_retry_ms);
+    let mut attempt = 0;
+    loop {
+        match connect(config, max_connections).await {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt < config.database.connect_retries => {
+                attempt += 1;
+                logging::error!(
+                    "{}, retrying in {:?} ({}/{})",
+                    e,
+                    wait,
+                    attempt,
+                    config.database.connect_retries
+                );
+                tokio::time::sleep(wait).await;
+                wait = (wait * 2).min(MAX_CONNECT_RETRY_WAIT);
+            }
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+// Connections are only opened when needed, so a replica that's down doesn't stop startup
+pub fn connect_replica(
+    config: &Config,
+    url: &str,
+    max_connections: u32,
+) -> Result<Pool<Postgres>, String> {
+    return Ok(PgPoolOptions::new()
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_millis(
+            config.database.read_acquire_timeout_ms,
+        ))
+        .connect_lazy_with(connect_options(config, url)?));
+}
+
+// The transaction was rolled back by the server and can run again as is
+fn is_rolled_back(e: &sqlx::Error) -> bool {
+    return match e {
+        // serialization_failure and deadlock_detected
+        sqlx::Error::Database(e) => matches!(e.code().as_deref(), Some("40001" | "40P01")),
+        _ => false,
+    };
+}
+
+// Worth another attempt, unless the error came from a COMMIT that may have gone through
+fn is_transient(e: &sqlx::Error) -> bool {
+    if is_rolled_back(e) {
+        return true;
+    }
+    return match e {
+        sqlx::Error::Io(_) | sqlx::Error::WorkerCrashed => true,
+        // connection_exception class and admin_shutdown
+        sqlx::Error::Database(e) => e
+            .code()
+            .is_some_and(|code| return code.starts_with("08") || code == "57P01"),
+        _ => false,
+    };
+}
+
+// Tells the circuit breaker whether a database call failed or got an answer
+pub trait Outcome {
+    fn failed(&self) -> bool;
+}
+
+impl<T> Outcome for Result<T, String> {
+    fn failed(&self) -> bool {
+        return self.is_err();
+    }
+}
+
 const INITIAL_USER_LIMITS: [i32; 5] = [100_000, 80_000, 1_000_000, 10_000_000, 500_000];
-pub async fn reset(pool: &Pool<Postgres>) {
+pub async fn reset(pool: &Pool<Postgres>) -> Result<(), String> {
     logging::log!("Initializing database");
-    let delete_result = sqlx::query("DELETE FROM users").execute(pool).await;
+    let mut postgres_transaction = pool
+        .begin()
+        .await
+        .map_err(|e| return format!("Error starting transaction: {}", e))?;
+    let delete_result = sqlx::query("DELETE FROM users")
+        .execute(&mut *postgres_transaction)
+        .await;
     match delete_result {
         Ok(rows) => {
             logging::log!("{} Users deleted successfully!", rows.rows_affected());
         }
         Err(e) => {
-            panic!("Error deleting users: {}", e);
+            return Err(format!("Error deleting users: {}", e));
         }
     };
-    for (i, limit) in INITIAL_USER_LIMITS.iter().enumerate() {
-        let user = User {
-            id: i32::try_from(i + 1).expect("Error converting user id"),
-            balance_limit: *limit,
-            balance: 0,
-            transactions_count: 0,
-            last_transaction: 0,
-            transactions: Default::default(),
-        };
-        match create_user(pool, user).await {
-            CreateUserResult::Ok(_) => {
-                logging::log!("User {} created successfully!", i + 1);
+    create_initial_users(&mut postgres_transaction).await?;
+    postgres_transaction
+        .commit()
+        .await
+        .map_err(|e| return format!("Error committing transaction: {}", e))?;
+    logging::log!("Database initialized");
+    return Ok(());
+}
+
+// Creates the initial users that don't exist, leaving the others untouched
+pub async fn seed(pool: &Pool<Postgres>) -> Result<u64, String> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .map_err(|e| return format!("Error acquiring connection: {}", e))?;
+    return create_initial_users(&mut connection).await;
+}
+
+async fn create_initial_users(connection: &mut PgConnection) -> Result<u64, String> {
+    let users = INITIAL_USER_LIMITS
+        .iter()
+        .enumerate()
+        .map(|(i, limit)| {
+            return User {
+                id: i32::try_from(i + 1).expect("Error converting user id"),
+                balance_limit: *limit,
+                balance: 0,
+                transactions_count: 0,
+                last_transaction: 0,
+                transactions: Default::default(),
+                version: 0,
+            };
+        })
+        .collect();
+    return create_users(connection, users).await;
+}
+
+// Creates the given users in one transaction, skipping ids that already exist
+pub async fn seed_users(pool: &Pool<Postgres>, users: Vec<User>) -> Result<u64, String> {
+    let mut postgres_transaction = pool
+        .begin()
+        .await
+        .map_err(|e| return format!("Error starting transaction: {}", e))?;
+    let created = create_users(&mut postgres_transaction, users).await?;
+    postgres_transaction
+        .commit()
+        .await
+        .map_err(|e| return format!("Error committing transaction: {}", e))?;
+    return Ok(created);
+}
+
+pub async fn delete_users(pool: &Pool<Postgres>, ids: &[i32]) -> Result<u64, String> {
+    return sqlx::query!("DELETE FROM users WHERE id = ANY($1)", ids)
+        .execute(pool)
+        .await
+        .map(|result| return result.rows_affected())
+        .map_err(|e| return format!("Error deleting users: {}", e));
+}
+
+async fn create_users(connection: &mut PgConnection, users: Vec<User>) -> Result<u64, String> {
+    let mut created = 0;
+    for user in users {
+        let id = user.id;
+        match create_user(&mut *connection, user).await {
+            CreateUserResult::Ok(rows) => {
+                logging::log!("User {} created successfully! ({} rows)", id, rows);
+                created += rows;
             }
             CreateUserResult::InternalError(e) => {
-                panic!("Error creating user {}: {}", i + 1, e);
+                return Err(format!("Error creating user {}: {}", id, e));
             }
         };
     }
+    return Ok(created);
+}
+
+pub async fn ping(pool: &Pool<Postgres>) -> Result<(), String> {
+    return match sqlx::query("SELECT 1").execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error pinging database: {}", e)),
+    };
 }
 
 pub enum ReadUserResult {
@@ -44,6 +223,12 @@ pub enum ReadUserResult {
     InternalError(String),
 }
 
+impl Outcome for ReadUserResult {
+    fn failed(&self) -> bool {
+        return matches!(self, ReadUserResult::InternalError(_));
+    }
+}
+
 pub async fn read_user(pool: Arc<Pool<Postgres>>, id: i32, user: &mut User) -> ReadUserResult {
     let db_user = match sqlx::query_as!(UserDb, "SELECT * FROM users WHERE id = $1", id)
         .fetch_optional(pool.as_ref())
@@ -61,28 +246,116 @@ pub async fn read_user(pool: Arc<Pool<Postgres>>, id: i32, user: &mut User) -> R
             user.balance_limit = db_user.balance_limit;
             user.transactions_count = db_user.transactions_count;
             user.last_transaction = db_user.last_transaction;
+            user.version = db_user.version;
             transaction::decode_transactions(db_user.encoded_transactions, &mut user.transactions);
             return ReadUserResult::Ok;
         }
         None => ReadUserResult::NotFound,
     };
 }
+pub async fn read_users(pool: &Pool<Postgres>) -> Result<Vec<User>, String> {
+    let users = read_user_rows(pool).await?;
+    return Ok(users.into_iter().map(User::from).collect());
+}
+
+// Rows as stored, without decoding the transactions
+pub async fn read_user_rows(pool: &Pool<Postgres>) -> Result<Vec<UserDb>, String> {
+    return sqlx::query_as!(UserDb, "SELECT * FROM users ORDER BY id")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| return format!("Error reading users: {}", e));
+}
+
+pub async fn has_ledger(pool: &Pool<Postgres>) -> Result<bool, String> {
+    return sqlx::query_scalar!("SELECT to_regclass('ledger') IS NOT NULL AS \"exists!\"")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| return format!("Error looking up the ledger table: {}", e));
+}
+
+// The ledger table doesn't always exist, so its queries aren't checked at compile time
+async fn ledger_balance(connection: &mut PgConnection, id: i32) -> Result<i64, String> {
+    return sqlx::query_scalar(
+        "SELECT COALESCE(SUM(CASE WHEN tipo = 'c' THEN valor ELSE -valor END), 0)::int8 FROM ledger WHERE user_id = $1",
+    )
+    .bind(id)
+    .fetch_one(connection)
+    .await
+    .map_err(|e| return format!("Error summing ledger of user {}: {}", id, e));
+}
+
+pub async fn ledger_balances(pool: &Pool<Postgres>) -> Result<HashMap<i32, i64>, String> {
+    let balances: Vec<(i32, i64)> = sqlx::query_as(
+        "SELECT user_id, SUM(CASE WHEN tipo = 'c' THEN valor ELSE -valor END)::int8 FROM ledger GROUP BY user_id",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| return format!("Error summing ledger: {}", e))?;
+    return Ok(balances.into_iter().collect());
+}
+
+// Locks the user, so the ledger sum can't change while the repair is computed,
+// and writes back what `repair` returns. Returns whether the user was changed.
+pub async fn repair_user<F>(
+    pool: &Pool<Postgres>,
+    id: i32,
+    with_ledger: bool,
+    repair: F,
+) -> Result<bool, String>
+where
+    F: FnOnce(&UserDb, Option<i64>) -> Option<UserDb>,
+{
+    let mut postgres_transaction = pool
+        .begin()
+        .await
+        .map_err(|e| return format!("Error starting transaction: {}", e))?;
+    let user = sqlx::query_as!(UserDb, "SELECT * FROM users WHERE id = $1 FOR UPDATE", id)
+        .fetch_one(&mut *postgres_transaction)
+        .await
+        .map_err(|e| return format!("Error reading user for update: {}", e))?;
+    let ledger_balance = if with_ledger {
+        Some(ledger_balance(&mut postgres_transaction, id).await?)
+    } else {
+        None
+    };
+    let repaired = match repair(&user, ledger_balance) {
+        Some(repaired) => repaired,
+        None => return Ok(false),
+    };
+    sqlx::query!(
+        "UPDATE users SET balance = $1, transactions_count = $2, last_transaction = $3, encoded_transactions = $4, version = version + 1 WHERE id = $5",
+        repaired.balance,
+        repaired.transactions_count,
+        repaired.last_transaction,
+        repaired.encoded_transactions,
+        id
+    )
+    .execute(&mut *postgres_transaction)
+    .await
+    .map_err(|e| return format!("Error updating user: {}", e))?;
+    postgres_transaction
+        .commit()
+        .await
+        .map_err(|e| return format!("Error committing transaction: {}", e))?;
+    return Ok(true);
+}
+
 pub enum CreateUserResult {
     Ok(u64),
     InternalError(String),
 }
 
-pub async fn create_user(pool: &Pool<Postgres>, user: User) -> CreateUserResult {
+pub async fn create_user(connection: &mut PgConnection, user: User) -> CreateUserResult {
     let insert_result = sqlx::query_as!(
             UserDb,
-            "INSERT INTO users (id, balance_limit, balance, transactions_count, last_transaction, encoded_transactions) VALUES ($1, $2, $3, $4, $5, $6)",
+            "INSERT INTO users (id, balance_limit, balance, transactions_count, last_transaction, encoded_transactions) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (id) DO NOTHING",
             user.id,
             user.balance_limit,
             user.balance,
             user.transactions_count,
             user.last_transaction,
             transaction::encode_transactions(&user.transactions)
-        ).execute(pool).await;
+        ).execute(connection).await;
     return match insert_result {
         Ok(rows) => CreateUserResult::Ok(rows.rows_affected()),
         Err(e) => {
@@ -93,23 +366,60 @@ pub async fn create_user(pool: &Pool<Postgres>, user: User) -> CreateUserResult
 }
 
 pub enum UpdateUserResult {
-    Ok(User),
+    Ok(Box<User>),
     NotFound,
-    Unprocessable(String),
+    Unprocessable(ApiError),
+    // The optimistic update lost every compare and swap
+    Conflict,
     InternalError(String),
 }
 
-pub async fn update_user_with_transaction(
+impl Outcome for UpdateUserResult {
+    fn failed(&self) -> bool {
+        return matches!(self, UpdateUserResult::InternalError(_));
+    }
+}
+
+pub async fn apply_transaction(
     pool: Arc<Pool<Postgres>>,
+    queues: &AccountQueues,
+    group: &GroupCommit,
+    storage: &StorageConfig,
     id: i32,
     transaction: &Transaction,
 ) -> UpdateUserResult {
-    let postgres_transaction = pool.begin().await;
-    let mut postgres_transaction = match postgres_transaction {
+    return match storage.update_strategy {
+        UpdateStrategy::Locking => {
+            update_user_with_transaction(pool, storage, id, transaction).await
+        }
+        UpdateStrategy::Optimistic => {
+            update_user_optimistic(&pool, id, transaction, storage.max_retries).await
+        }
+        UpdateStrategy::Function => update_user_with_function(&pool, id, transaction).await,
+        UpdateStrategy::Queued => queues.apply(id, transaction.clone()).await,
+        UpdateStrategy::Grouped => group.apply(id, transaction.clone()).await,
+    };
+}
+
+fn failed_batch(size: usize, error: &str) -> Vec<UpdateUserResult> {
+    return (0..size)
+        .map(|_| return UpdateUserResult::InternalError(error.to_string()))
+        .collect();
+}
+
+// Applies the transactions of one account in order in a single database
+// transaction. Each one is checked against the balance the previous ones left,
+// a refused transaction doesn't affect the others.
+pub async fn update_user_with_transactions(
+    pool: &Pool<Postgres>,
+    id: i32,
+    transactions: &[Transaction],
+) -> Vec<UpdateUserResult> {
+    let mut postgres_transaction = match pool.begin().await {
         Ok(transaction) => transaction,
         Err(e) => {
             let error_str = format!("Error starting transaction: {}", e);
-            return UpdateUserResult::InternalError(error_str);
+            return failed_batch(transactions.len(), &error_str);
         }
     };
     let db_user = match sqlx::query_as!(UserDb, "SELECT * FROM users WHERE id = $1 FOR UPDATE", id)
@@ -118,37 +428,371 @@ pub async fn update_user_with_transaction(
     {
         Ok(Some(user)) => user,
         Ok(None) => {
-            return UpdateUserResult::NotFound;
+            return transactions
+                .iter()
+                .map(|_| return UpdateUserResult::NotFound)
+                .collect();
         }
         Err(e) => {
             let error_str = format!("Error reading user for update: {}", e);
+            return failed_batch(transactions.len(), &error_str);
+        }
+    };
+
+    let mut user = match User::try_from_db(db_user) {
+        Ok(user) => user,
+        Err(e) => {
+            let error_str = format!("Error decoding transactions of user {}: {}", id, e);
+            return failed_batch(transactions.len(), &error_str);
+        }
+    };
+    let mut results = Vec::with_capacity(transactions.len());
+    for transaction in transactions {
+        let transaction_result = user.compute_transaction(transaction);
+        if let Some(error) = transaction_result.error() {
+            logging::log!("Transaction rejected for user {}: {}", id, error.message);
+            results.push(UpdateUserResult::Unprocessable(error));
+            continue;
+        }
+        user.add_transaction(transaction);
+        results.push(UpdateUserResult::Ok(Box::new(user.clone())));
+    }
+    if !results
+        .iter()
+        .any(|result| return matches!(result, UpdateUserResult::Ok(_)))
+    {
+        return results;
+    }
+
+    // The version went up once per applied transaction, the row is locked
+    let update_result = sqlx::query!(
+        "UPDATE users SET balance = $1, transactions_count = $2, last_transaction = $3, encoded_transactions = $4, version = $5 WHERE id = $6",
+        user.balance,
+        user.transactions_count,
+        user.last_transaction,
+        transaction::encode_transactions(&user.transactions),
+        user.version,
+        id
+    ).execute(&mut *postgres_transaction).await;
+    if let Err(e) = update_result {
+        let error_str = format!("Error updating user: {}", e);
+        return failed_batch(transactions.len(), &error_str);
+    }
+    if let Err(e) = postgres_transaction.commit().await {
+        let error_str = format!("Error committing transaction: {}", e);
+        return failed_batch(transactions.len(), &error_str);
+    }
+    return results;
+}
+
+// The limit check, balance update and ring buffer write all happen in the
+// apply_transaction database function, which returns the updated row
+pub async fn update_user_with_function(
+    pool: &Pool<Postgres>,
+    id: i32,
+    transaction: &Transaction,
+) -> UpdateUserResult {
+    let applied = sqlx::query!(
+        r#"SELECT result AS "result!", balance, balance_limit, transactions_count, last_transaction, encoded_transactions, version FROM apply_transaction($1, $2, $3, $4, $5)"#,
+        id,
+        transaction.valor,
+        transaction.tipo,
+        transaction.descricao,
+        clock::timestamp::format(&transaction.realizada_em)
+    )
+    .fetch_one(pool)
+    .await;
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(e) => {
+            let error_str = format!("Error applying transaction: {}", e);
             return UpdateUserResult::InternalError(error_str);
         }
     };
 
-    let mut user = User::from(db_user);
+    let transaction_result = match applied.result.as_str() {
+        "ok" => TransactionResult::Ok,
+        "limit_exceeded" => TransactionResult::LimitExceeded,
+        "balance_out_of_range" => TransactionResult::BalanceOutOfRange,
+        "invalid_kind" => TransactionResult::InvalidTransactionKind(transaction.tipo.clone()),
+        "not_found" => {
+            return UpdateUserResult::NotFound;
+        }
+        other => {
+            let error_str = format!("Unknown apply_transaction result {}", other);
+            return UpdateUserResult::InternalError(error_str);
+        }
+    };
+    if let Some(error) = transaction_result.error() {
+        logging::log!("Transaction rejected for user {}: {}", id, error.message);
+        return UpdateUserResult::Unprocessable(error);
+    }
+    let (
+        Some(balance),
+        Some(balance_limit),
+        Some(transactions_count),
+        Some(last_transaction),
+        Some(version),
+    ) = (
+        applied.balance,
+        applied.balance_limit,
+        applied.transactions_count,
+        applied.last_transaction,
+        applied.version,
+    )
+    else {
+        let error_str = format!(
+            "apply_transaction returned an incomplete row for user {}",
+            id
+        );
+        return UpdateUserResult::InternalError(error_str);
+    };
+    return UpdateUserResult::Ok(Box::new(User::from(UserDb {
+        id,
+        balance_limit,
+        balance,
+        transactions_count,
+        last_transaction,
+        encoded_transactions: applied.encoded_transactions,
+        version,
+    })));
+}
+
+// Random wait before the next optimistic attempt, so writers that conflicted
+// don't read and write the account again in lockstep. RandomState is seeded
+// differently every time it's created.
+fn optimistic_retry_wait(attempt: u32) -> Duration {
+    let max_wait = OPTIMISTIC_RETRY_WAIT * (1 << attempt.min(6));
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let max_nanos = u64::try_from(max_wait.as_nanos()).unwrap_or(u64::MAX);
+    return Duration::from_nanos(hasher.finish() % max_nanos);
+}
 
-    match user.compute_transaction(transaction) {
-        TransactionResult::Ok => {
-            logging::log!("Transaction computed successfully! Adding to list of transactions.");
-            user.add_transaction(transaction);
+// Reads the user without locking and only writes it back if nobody else did in
+// between, otherwise reads it again, up to max_retries times
+pub async fn update_user_optimistic(
+    pool: &Pool<Postgres>,
+    id: i32,
+    transaction: &Transaction,
+    max_retries: u32,
+) -> UpdateUserResult {
+    for attempt in 0..=max_retries {
+        let db_user = match sqlx::query_as!(UserDb, "SELECT * FROM users WHERE id = $1", id)
+            .fetch_optional(pool)
+            .await
+        {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                return UpdateUserResult::NotFound;
+            }
+            Err(e) => {
+                let error_str = format!("Error reading user: {}", e);
+                return UpdateUserResult::InternalError(error_str);
+            }
+        };
+        let version = db_user.version;
+
+        let mut user = match User::try_from_db(db_user) {
+            Ok(user) => user,
+            Err(e) => {
+                let error_str = format!("Error decoding transactions of user {}: {}", id, e);
+                return UpdateUserResult::InternalError(error_str);
+            }
+        };
+        let transaction_result = user.compute_transaction(transaction);
+        if let Some(error) = transaction_result.error() {
+            logging::log!("Transaction rejected for user {}: {}", id, error.message);
+            return UpdateUserResult::Unprocessable(error);
         }
-        // Return None if the transaction is invalid
-        TransactionResult::LimitExceeded => {
-            let error_string = format!("Limit exceeded for user {}", id);
-            return UpdateUserResult::Unprocessable(error_string);
+        user.add_transaction(transaction);
+        let update_result = sqlx::query!(
+            "UPDATE users SET balance = $1, transactions_count = $2, last_transaction = $3, encoded_transactions = $4, version = version + 1 WHERE id = $5 AND version = $6",
+            user.balance,
+            user.transactions_count,
+            user.last_transaction,
+            transaction::encode_transactions(&user.transactions),
+            id,
+            version
+        ).execute(pool).await;
+
+        match update_result {
+            Ok(result) if result.rows_affected() == 1 => {
+                return UpdateUserResult::Ok(Box::new(user));
+            }
+            Ok(_) => {
+                logging::log!(
+                    "Version {} of user {} changed, attempt {}",
+                    version,
+                    id,
+                    attempt
+                );
+                if attempt < max_retries {
+                    tokio::time::sleep(optimistic_retry_wait(attempt)).await;
+                }
+            }
+            Err(e) => {
+                let error_string = format!("Error updating user: {}", e);
+                return UpdateUserResult::InternalError(error_string);
+            }
+        };
+    }
+    return UpdateUserResult::Conflict;
+}
+
+// Applies transactions of any accounts in order in a single database
+// transaction. The accounts are locked by id so concurrent groups can't
+// deadlock, and written back with one UPDATE.
+pub async fn update_users_with_transactions(
+    pool: &Pool<Postgres>,
+    transactions: &[(i32, Transaction)],
+) -> Vec<UpdateUserResult> {
+    let mut postgres_transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            let error_str = format!("Error starting transaction: {}", e);
+            return failed_batch(transactions.len(), &error_str);
+        }
+    };
+    let mut ids: Vec<i32> = transactions.iter().map(|(id, _)| return *id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let db_users = sqlx::query_as!(
+        UserDb,
+        "SELECT * FROM users WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+        &ids
+    )
+    .fetch_all(&mut *postgres_transaction)
+    .await;
+    let db_users = match db_users {
+        Ok(db_users) => db_users,
+        Err(e) => {
+            let error_str = format!("Error reading users for update: {}", e);
+            return failed_batch(transactions.len(), &error_str);
         }
-        TransactionResult::InvalidDescription => {
-            let error_string = format!("Invalid description for user {}", id);
-            return UpdateUserResult::Unprocessable(error_string);
+    };
+    // An account that can't be decoded fails its own transactions only
+    let mut users: HashMap<i32, (User, bool)> = HashMap::with_capacity(db_users.len());
+    let mut undecodable: HashMap<i32, String> = HashMap::new();
+    for db_user in db_users {
+        let id = db_user.id;
+        match User::try_from_db(db_user) {
+            Ok(user) => {
+                users.insert(id, (user, false));
+            }
+            Err(e) => {
+                undecodable.insert(id, e);
+            }
+        };
+    }
+
+    let mut results = Vec::with_capacity(transactions.len());
+    for (id, transaction) in transactions {
+        if let Some(e) = undecodable.get(id) {
+            let error_str = format!("Error decoding transactions of user {}: {}", id, e);
+            results.push(UpdateUserResult::InternalError(error_str));
+            continue;
         }
-        TransactionResult::InvalidTransactionKind(t) => {
-            let error_string = format!("Invalid transaction kind {} for user {}", t, id);
-            return UpdateUserResult::Unprocessable(error_string);
+        let Some((user, changed)) = users.get_mut(id) else {
+            results.push(UpdateUserResult::NotFound);
+            continue;
+        };
+        let transaction_result = user.compute_transaction(transaction);
+        if let Some(error) = transaction_result.error() {
+            logging::log!("Transaction rejected for user {}: {}", id, error.message);
+            results.push(UpdateUserResult::Unprocessable(error));
+            continue;
+        }
+        user.add_transaction(transaction);
+        *changed = true;
+        results.push(UpdateUserResult::Ok(Box::new(user.clone())));
+    }
+
+    let changed: Vec<&User> = users
+        .values()
+        .filter(|(_, changed)| return *changed)
+        .map(|(user, _)| return user)
+        .collect();
+    if changed.is_empty() {
+        return results;
+    }
+    let update_result = sqlx::query!(
+        "UPDATE users SET balance = changed.balance, transactions_count = changed.transactions_count, last_transaction = changed.last_transaction, encoded_transactions = changed.encoded_transactions, version = changed.version
+        FROM UNNEST($1::int[], $2::int[], $3::int[], $4::int[], $5::bytea[], $6::bigint[]) AS changed (id, balance, transactions_count, last_transaction, encoded_transactions, version)
+        WHERE users.id = changed.id",
+        &changed.iter().map(|user| return user.id).collect::<Vec<_>>(),
+        &changed.iter().map(|user| return user.balance).collect::<Vec<_>>(),
+        &changed.iter().map(|user| return user.transactions_count).collect::<Vec<_>>(),
+        &changed.iter().map(|user| return user.last_transaction).collect::<Vec<_>>(),
+        &changed
+            .iter()
+            .map(|user| return transaction::encode_transactions(&user.transactions))
+            .collect::<Vec<_>>(),
+        &changed.iter().map(|user| return user.version).collect::<Vec<_>>()
+    )
+    .execute(&mut *postgres_transaction)
+    .await;
+    if let Err(e) = update_result {
+        let error_str = format!("Error updating users: {}", e);
+        return failed_batch(transactions.len(), &error_str);
+    }
+    if let Err(e) = postgres_transaction.commit().await {
+        let error_str = format!("Error committing transaction: {}", e);
+        return failed_batch(transactions.len(), &error_str);
+    }
+    return results;
+}
+
+enum Attempt {
+    Done(UpdateUserResult),
+    // Retry(String) is the error of the failed attempt
+    Retry(String),
+}
+
+fn failed_attempt(context: &str, e: &sqlx::Error, retryable: bool) -> Attempt {
+    let error_str = format!("{}: {}", context, e);
+    if retryable {
+        return Attempt::Retry(error_str);
+    }
+    return Attempt::Done(UpdateUserResult::InternalError(error_str));
+}
+
+async fn try_update_user_with_transaction(
+    pool: &Pool<Postgres>,
+    id: i32,
+    transaction: &Transaction,
+) -> Attempt {
+    let postgres_transaction = pool.begin().await;
+    let mut postgres_transaction = match postgres_transaction {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            return failed_attempt("Error starting transaction", &e, is_transient(&e));
+        }
+    };
+    let db_user = match sqlx::query_as!(UserDb, "SELECT * FROM users WHERE id = $1 FOR UPDATE", id)
+        .fetch_optional(&mut *postgres_transaction)
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return Attempt::Done(UpdateUserResult::NotFound);
+        }
+        Err(e) => {
+            return failed_attempt("Error reading user for update", &e, is_transient(&e));
         }
     };
+
+    let mut user = User::from(db_user);
+
+    let transaction_result = user.compute_transaction(transaction);
+    if let Some(error) = transaction_result.error() {
+        logging::log!("Transaction rejected for user {}: {}", id, error.message);
+        return Attempt::Done(UpdateUserResult::Unprocessable(error));
+    }
+    logging::log!("Transaction computed successfully! Adding to list of transactions.");
+    user.add_transaction(transaction);
     let update_result = sqlx::query!(
-        "UPDATE users SET balance = $1, transactions_count = $2, last_transaction = $3, encoded_transactions = $4 WHERE id = $5",
+        "UPDATE users SET balance = $1, transactions_count = $2, last_transaction = $3, encoded_transactions = $4, version = version + 1 WHERE id = $5",
         user.balance,
         user.transactions_count,
         user.last_transaction,
@@ -159,15 +803,124 @@ pub async fn update_user_with_transaction(
     match update_result {
         Ok(_) => {}
         Err(e) => {
-            let error_string = format!("Error updating user: {}", e);
-            return UpdateUserResult::InternalError(error_string);
+            return failed_attempt("Error updating user", &e, is_transient(&e));
         }
     };
     return match postgres_transaction.commit().await {
-        Ok(()) => UpdateUserResult::Ok(user),
+        Ok(()) => Attempt::Done(UpdateUserResult::Ok(Box::new(user))),
+        Err(e) => {
+            return failed_attempt("Error committing transaction", &e, is_rolled_back(&e));
+        }
+    };
+}
+
+// Runs the transaction again after serialization failures, deadlocks and lost
+// connections, waiting twice as long each time
+pub async fn update_user_with_transaction(
+    pool: Arc<Pool<Postgres>>,
+    storage: &StorageConfig,
+    id: i32,
+    transaction: &Transaction,
+) -> UpdateUserResult {
+    let mut wait = Duration::from_millis(storage.transient_backoff_ms);
+    let mut attempt = 0;
+    loop {
+        match try_update_user_with_transaction(&pool, id, transaction).await {
+            Attempt::Done(result) => return result,
+            Attempt::Retry(e) if attempt < storage.transient_retries => {
+                attempt += 1;
+                logging::error!(
+                    "{}, retrying the transaction of user {} in {:?} ({}/{})",
+                    e,
+                    id,
+                    wait,
+                    attempt,
+                    storage.transient_retries
+                );
+                tokio::time::sleep(wait).await;
+                wait *= 2;
+            }
+            Attempt::Retry(e) => return UpdateUserResult::InternalError(e),
+        };
+    }
+}
+
+pub enum UpdateLimitResult {
+    // Ok(i32) is the user's current balance
+    Ok(i32),
+    NotFound,
+    Unprocessable(ApiError),
+    InternalError(String),
+}
+
+pub async fn update_limit(pool: &Pool<Postgres>, id: i32, balance_limit: i32) -> UpdateLimitResult {
+    let update_result = sqlx::query_scalar!(
+        "UPDATE users SET balance_limit = $2, version = version + 1 WHERE id = $1 AND balance >= -$2::int RETURNING balance",
+        id,
+        balance_limit
+    )
+    .fetch_optional(pool)
+    .await;
+    match update_result {
+        Ok(Some(balance)) => return UpdateLimitResult::Ok(balance),
+        Ok(None) => {}
+        Err(e) => {
+            let error_string = format!("Error updating limit: {}", e);
+            return UpdateLimitResult::InternalError(error_string);
+        }
+    };
+    // Nothing was updated, either the user doesn't exist or the balance is below the new limit
+    let mut user = User {
+        id,
+        balance_limit: 0,
+        balance: 0,
+        transactions_count: 0,
+        last_transaction: 0,
+        transactions: Default::default(),
+        version: 0,
+    };
+    return match read_user(Arc::new(pool.clone()), id, &mut user).await {
+        ReadUserResult::Ok => UpdateLimitResult::Unprocessable(
+            ApiError::new(
+                "limit_below_balance",
+                format!(
+                    "Balance {} is below the limit {}",
+                    user.balance, -balance_limit
+                ),
+            )
+            .field("limite"),
+        ),
+        ReadUserResult::NotFound => UpdateLimitResult::NotFound,
+        ReadUserResult::InternalError(e) => UpdateLimitResult::InternalError(e),
+    };
+}
+
+pub enum ReadApiKeyResult {
+    Ok(ApiKey),
+    NotFound,
+    InternalError(String),
+}
+
+impl Outcome for ReadApiKeyResult {
+    fn failed(&self) -> bool {
+        return matches!(self, ReadApiKeyResult::InternalError(_));
+    }
+}
+
+pub async fn read_api_key(pool: &Pool<Postgres>, key_hash: &[u8]) -> ReadApiKeyResult {
+    let api_key = sqlx::query_as!(
+        ApiKey,
+        "SELECT client_ids, is_admin FROM api_keys WHERE key_hash = $1",
+        key_hash
+    )
+    .fetch_optional(pool)
+    .await;
+    return match api_key {
+        Ok(Some(api_key)) => ReadApiKeyResult::Ok(api_key),
+        Ok(None) => ReadApiKeyResult::NotFound,
         Err(e) => {
-            let error_string = format!("Error committing transaction: {}", e);
-            return UpdateUserResult::InternalError(error_string);
+            let error_str = format!("Error reading api key: {}", e);
+            return ReadApiKeyResult::InternalError(error_str);
         }
     };
 }
@@ -0,0 +1,92 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{Pool, Postgres};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    db::{self, UpdateUserResult},
+    logging,
+    transaction::Transaction,
+};
+
+struct PendingTransaction {
+    id: i32,
+    transaction: Transaction,
+    reply: oneshot::Sender<UpdateUserResult>,
+}
+
+// Collects the transactions of every account arriving within a window and
+// applies them in one database transaction. Each client is answered once the
+// whole group is committed, a transaction refused by compute_transaction
+// doesn't affect the rest of its group.
+pub struct GroupCommit {
+    sender: mpsc::UnboundedSender<PendingTransaction>,
+}
+
+impl GroupCommit {
+    // Spawns the committing task on the current runtime
+    pub fn new(pool: Arc<Pool<Postgres>>, window: Duration, max_size: usize) -> GroupCommit {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(pool, window, max_size, receiver));
+        return GroupCommit { sender };
+    }
+
+    pub async fn apply(&self, id: i32, transaction: Transaction) -> UpdateUserResult {
+        let (reply, result) = oneshot::channel();
+        let pending = PendingTransaction {
+            id,
+            transaction,
+            reply,
+        };
+        if self.sender.send(pending).is_err() {
+            return UpdateUserResult::InternalError("Group commit stopped".to_string());
+        }
+        return result.await.unwrap_or_else(|_| {
+            return UpdateUserResult::InternalError("Group commit stopped".to_string());
+        });
+    }
+}
+
+// Waits for a first transaction, then for the window to pass or the group to fill up
+async fn collect(
+    receiver: &mut mpsc::UnboundedReceiver<PendingTransaction>,
+    window: Duration,
+    max_size: usize,
+) -> Option<Vec<PendingTransaction>> {
+    let first = receiver.recv().await?;
+    let mut group = vec![first];
+    let deadline = tokio::time::Instant::now() + window;
+    while group.len() < max_size {
+        match tokio::time::timeout_at(deadline, receiver.recv()).await {
+            Ok(Some(pending)) => group.push(pending),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    return Some(group);
+}
+
+async fn run(
+    pool: Arc<Pool<Postgres>>,
+    window: Duration,
+    max_size: usize,
+    mut receiver: mpsc::UnboundedReceiver<PendingTransaction>,
+) {
+    while let Some(group) = collect(&mut receiver, window, max_size).await {
+        logging::log!("Committing a group of {} transactions", group.len());
+        let mut transactions = Vec::with_capacity(group.len());
+        let mut replies = Vec::with_capacity(group.len());
+        for pending in group {
+            transactions.push((pending.id, pending.transaction));
+            replies.push(pending.reply);
+        }
+        let results = db::update_users_with_transactions(&pool, &transactions).await;
+        // A request may have gone away, its transaction stays applied
+        replies
+            .into_iter()
+            .zip(results)
+            .for_each(|(reply, result)| {
+                let _ = reply.send(result);
+            });
+    }
+    logging::log!("Group commit stopped");
+}
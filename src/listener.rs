@@ -0,0 +1,205 @@
+use std::{
+    net::IpAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+};
+
+use crate::{
+    admin,
+    config::{ListenerConfig, RouteSet, UnixSocketConfig},
+    handle_request, logging, responses,
+    state::AppState,
+    tls::TlsReloader,
+};
+
+pub fn bind_tcp(
+    config: &ListenerConfig,
+    backlog: u32,
+    reuse_port: bool,
+) -> Result<TcpListener, String> {
+    let addr = config.address;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .map_err(|e| return format!("failed to create socket: {}", e))?;
+    socket
+        .set_reuse_address(true)
+        .map_err(|e| return format!("failed to set reuse address: {}", e))?;
+    // Lets every worker bind its own listener, the kernel balances connections between them
+    if reuse_port {
+        socket
+            .set_reuse_port(true)
+            .map_err(|e| return format!("failed to set reuse port: {}", e))?;
+    }
+    if addr.is_ipv6() {
+        socket
+            .set_only_v6(!config.dual_stack)
+            .map_err(|e| return format!("failed to configure dual stack on {}: {}", addr, e))?;
+    }
+    socket
+        .set_nonblocking(true)
+        .map_err(|e| return format!("failed to set non blocking: {}", e))?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| return format!("failed to bind to {}: {}", addr, e))?;
+    socket
+        .listen(i32::try_from(backlog).unwrap_or(i32::MAX))
+        .map_err(|e| return format!("failed to listen on {}: {}", addr, e))?;
+    return TcpListener::from_std(socket.into())
+        .map_err(|e| return format!("failed to register listener on {}: {}", addr, e));
+}
+
+// A socket file nobody accepts on is left behind by a process that didn't exit cleanly
+fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", path.display()));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(format!("{} is in use by another process", path.display()));
+    }
+    logging::log!("Removing stale socket {}", path.display());
+    return std::fs::remove_file(path)
+        .map_err(|e| return format!("failed to remove stale socket {}: {}", path.display(), e));
+}
+
+// Bound once per process, every worker accepts from a clone of the same socket
+pub fn bind_unix(config: &UnixSocketConfig) -> Result<std::os::unix::net::UnixListener, String> {
+    let path = config.path.as_deref().unwrap_or(Path::new(""));
+    if config.remove_stale {
+        remove_stale_socket(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)
+        .map_err(|e| return format!("failed to bind to {}: {}", path.display(), e))?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| return format!("failed to set non blocking: {}", e))?;
+    let mode = config.mode().unwrap_or(0o660);
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+        return format!("failed to set permissions on {}: {}", path.display(), e);
+    })?;
+    return Ok(listener);
+}
+
+pub async fn serve_tcp(
+    listener: TcpListener,
+    routes: RouteSet,
+    state: Arc<AppState>,
+    tls: Option<Arc<TlsReloader>>,
+) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d
+        let peer = Some(peer.ip().to_canonical());
+        tokio::spawn(accept_connection(
+            state.clone(),
+            tls.clone(),
+            stream,
+            routes,
+            peer,
+        ));
+    }
+    logging::error!("TCP listener stopped accepting connections");
+}
+
+pub async fn serve_unix(
+    listener: UnixListener,
+    state: Arc<AppState>,
+    tls: Option<Arc<TlsReloader>>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(accept_connection(
+            state.clone(),
+            tls.clone(),
+            stream,
+            RouteSet::Api,
+            None,
+        ));
+    }
+    logging::error!("Unix socket listener stopped accepting connections");
+}
+
+async fn accept_connection<S>(
+    state: Arc<AppState>,
+    tls: Option<Arc<TlsReloader>>,
+    stream: S,
+    routes: RouteSet,
+    peer: Option<IpAddr>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    state.worker_metrics().connection_opened();
+    match tls {
+        Some(tls) => {
+            let read_timeout = Duration::from_millis(state.config.server.read_timeout_ms);
+            match tokio::time::timeout(read_timeout, tls.acceptor().accept(stream)).await {
+                Ok(Ok(stream)) => handle_connection(&state, stream, routes, peer).await,
+                Ok(Err(e)) => {
+                    logging::error!("TLS handshake failed: {}", e);
+                }
+                Err(_) => {
+                    logging::error!("Timed out during TLS handshake");
+                }
+            };
+        }
+        None => handle_connection(&state, stream, routes, peer).await,
+    };
+    state.worker_metrics().connection_closed();
+}
+
+async fn handle_connection<S>(
+    state: &Arc<AppState>,
+    mut stream: S,
+    routes: RouteSet,
+    peer: Option<IpAddr>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request_buffer = &mut [0; 512];
+    let read_timeout = Duration::from_millis(state.config.server.read_timeout_ms);
+    let read_result = tokio::time::timeout(read_timeout, stream.read(request_buffer)).await;
+    let request_size = match read_result {
+        Ok(Ok(request_size)) => request_size,
+        Ok(Err(error)) => {
+            logging::error!("Failed to read from connection: {}", error);
+            return;
+        }
+        Err(_) => {
+            logging::error!("Timed out reading from connection");
+            return;
+        }
+    };
+
+    let started_at = Instant::now();
+    let response = match routes {
+        RouteSet::Api => handle_request(state, request_buffer, request_size, peer).await,
+        RouteSet::Admin => admin::handle_request(state, &request_buffer[..request_size]).await,
+    };
+    let status = response.status_code();
+    let respond_result = responses::respond(&mut stream, response).await;
+    state
+        .worker_metrics()
+        .record_response(status, started_at.elapsed());
+    match respond_result {
+        Ok(()) => {}
+        Err(e) => {
+            logging::error!("Failed to write to connection: {}", e);
+            return;
+        }
+    };
+    // Lets TLS clients see a clean close_notify instead of a truncated stream
+    match stream.shutdown().await {
+        Ok(()) => {}
+        Err(e) => {
+            logging::log!("Failed to shut down connection: {}", e);
+        }
+    };
+}
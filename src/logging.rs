@@ -1,4 +1,6 @@
-use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::LogConfig;
 
 pub fn into_log_json(buffer: &[u8]) -> String {
     return String::from_utf8_lossy(buffer)
@@ -8,29 +10,33 @@ pub fn into_log_json(buffer: &[u8]) -> String {
         .replace('\0', "\\0");
 }
 
-pub fn get_enable_log() -> &'static bool {
-    static INIT: OnceLock<bool> = OnceLock::new();
-    return INIT.get_or_init(|| {
-        return match std::env::var("ENABLE_LOG") {
-            Ok(val) => val == "true" || val == "1",
-            Err(_) => false,
-        };
-    });
+static ENABLE_LOG: AtomicBool = AtomicBool::new(false);
+static ENABLE_LOG_ERROR: AtomicBool = AtomicBool::new(true);
+
+pub fn init(config: &LogConfig) {
+    set_enable_log(config.enabled);
+    set_enable_log_error(config.errors_enabled);
+}
+
+pub fn set_enable_log(enabled: bool) {
+    ENABLE_LOG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_enable_log_error(enabled: bool) {
+    ENABLE_LOG_ERROR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn get_enable_log() -> bool {
+    return ENABLE_LOG.load(Ordering::Relaxed);
 }
 
-pub fn get_enable_log_error() -> &'static bool {
-    static INIT: OnceLock<bool> = OnceLock::new();
-    return INIT.get_or_init(|| {
-        return match std::env::var("ENABLE_ERROR_LOG") {
-            Ok(val) => val != "false" && val != "0",
-            Err(_) => true,
-        };
-    });
+pub fn get_enable_log_error() -> bool {
+    return ENABLE_LOG_ERROR.load(Ordering::Relaxed);
 }
 
 macro_rules! log {
     ($($arg:expr),*) => {
-        if *crate::logging::get_enable_log() {
+        if crate::logging::get_enable_log() {
             println!($($arg),*);
         }
     };
@@ -38,7 +44,7 @@ macro_rules! log {
 
 macro_rules! error {
     ($($arg:expr),*) => {
-        if *crate::logging::get_enable_log_error() {
+        if crate::logging::get_enable_log_error() {
             println!($($arg),*);
         }
     };
@@ -5,95 +5,249 @@
 #![allow(clippy::single_match_else)]
 #![allow(clippy::uninlined_format_args)]
 
+mod account_queue;
+mod admin;
+mod auth;
 mod bank_statement;
+mod circuit_breaker;
+mod clock;
+mod commands;
+mod config;
 mod db;
+mod group_commit;
+mod listener;
 mod logging;
+mod metrics;
+mod migrations;
+mod rate_limit;
+mod replica;
+mod request;
 mod responses;
+mod signing;
+mod state;
+mod statement_cache;
+mod tls;
 mod transaction;
 mod user;
+mod user_changes;
+mod verify;
+mod worker;
 
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
+use auth::ApiKeyCache;
+use circuit_breaker::CircuitBreaker;
+use clap::Parser;
+use config::{CliArgs, Command, Config, ServeArgs};
+use metrics::MetricsRegistry;
+use rate_limit::RateLimiter;
+use replica::RecentWrites;
 use responses::ResponseType;
-use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use tokio::{io::AsyncReadExt, net::TcpSocket};
+use signing::NonceCache;
+use state::AppState;
+use statement_cache::StatementCache;
+use tls::TlsReloader;
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn main() {
     dotenvy::dotenv().ok();
-    let args: Vec<String> = std::env::args().collect();
-    let default_port = "9999".to_string();
-    let port = args.get(1).unwrap_or(&default_port);
-    let should_init_db = args.get(2).map(|s| return s.as_str()) == Some("resetDb");
-    let max_connections = std::env::var("MAX_CONNECTIONS")
-        .ok()
-        .and_then(|m| return m.parse::<u32>().ok())
-        .unwrap_or(15);
-    println!("Max database connections: {max_connections}");
-
-    let pool_result = PgPoolOptions::new()
-        .max_connections(max_connections)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
-        .await;
-
-    let pool = match pool_result {
-        Ok(pool) => Arc::new(pool),
+    let args = CliArgs::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
         Err(e) => {
-            panic!("Failed to connect to database: {}", e);
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(commands::EXIT_INVALID_CONFIG);
         }
     };
-    if should_init_db {
-        logging::log!("Resetting db");
-        db::reset(&pool).await;
+    logging::init(&config.log);
+
+    let exit_code = match args.command {
+        None => serve(config, &ServeArgs::default()),
+        Some(Command::Serve(serve_args)) => serve(config, &serve_args),
+        Some(Command::Migrate) => commands::migrate(&config),
+        Some(Command::Reset) => commands::reset(&config),
+        Some(Command::Seed { file }) => commands::seed(&config, file.as_deref()),
+        Some(Command::Verify { repair }) => commands::verify(&config, repair),
+        Some(Command::Export { output, client }) => {
+            commands::export(&config, output.as_deref(), client)
+        }
+        Some(Command::Bench {
+            requests,
+            concurrency,
+            accounts,
+        }) => commands::bench(&config, requests, concurrency, accounts),
+    };
+    std::process::exit(exit_code);
+}
+
+fn serve(config: Config, args: &ServeArgs) -> i32 {
+    let max_connections = config.database.max_connections;
+    println!("Max database connections: {max_connections}");
+    let prepare_result = worker::new_runtime().block_on(async {
+        let pool = db::connect_with_retries(&config, 1).await?;
+        if config.database.migrate_on_start {
+            for version in migrations::run(&pool).await? {
+                println!("Applied migration {}", migrations::describe(version));
+            }
+        } else {
+            if let Some(version) = migrations::pending(&pool).await?.first() {
+                return Err(format!(
+                    "Migration {} is pending, run the migrate command first",
+                    migrations::describe(*version)
+                ));
+            }
+        }
+        if args.reset_db {
+            logging::log!("Resetting db");
+            db::reset(&pool).await?;
+        }
+        return Ok(());
+    });
+    if let Err(e) = prepare_result {
+        eprintln!("Failed to prepare database: {e}");
+        return commands::EXIT_FAILURE;
     }
-    let addr: std::net::SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
-    let socket = TcpSocket::new_v4().expect("Failed to create socket");
-    socket
-        .set_reuseaddr(true)
-        .expect("Failed to set reuse address");
-    socket.bind(addr).expect("Failed to bind to address");
-
-    let listener = socket.listen(2048).expect("Failed to listen on address");
-
-    logging::log!("Listening for connections on port {port}");
-
-    while let Ok((mut stream, _)) = listener.accept().await {
-        let pool_clone = pool.clone();
-        let request_buffer = &mut [0; 512];
-        let read_result = stream.read(request_buffer).await;
-        let request_size = match read_result {
-            Ok(request_size) => request_size,
-            Err(error) => {
-                logging::error!("Failed to read from connection: {}", error);
-                return;
+
+    let tls = if config.tls.enabled {
+        match TlsReloader::new(&config.tls) {
+            Ok(tls) => Some(Arc::new(tls)),
+            Err(e) => {
+                eprintln!("Failed to load TLS certificate: {e}");
+                return commands::EXIT_INVALID_CONFIG;
             }
-        };
+        }
+    } else {
+        None
+    };
 
-        let response = handle_request(pool_clone, request_buffer, request_size).await;
-        match responses::respond(stream, response).await {
-            Ok(()) => {}
+    let unix_listener = match &config.unix_socket.path {
+        Some(path) => match listener::bind_unix(&config.unix_socket) {
+            Ok(listener) => {
+                logging::log!("Listening for connections on {}", path.display());
+                Some(listener)
+            }
             Err(e) => {
-                logging::error!("Failed to write to connection: {}", e);
+                eprintln!("Failed to start unix socket listener: {e}");
+                return commands::EXIT_FAILURE;
             }
-        };
+        },
+        None => None,
+    };
+
+    let workers = config.server.workers;
+    let shared = worker::Shared {
+        api_keys: Arc::new(ApiKeyCache::new(Duration::from_millis(
+            config.auth.cache_ttl_ms,
+        ))),
+        nonces: Arc::new(NonceCache::new(config.signing.nonce_cache_size)),
+        rate_limiter: Arc::new(RateLimiter::new()),
+        metrics: Arc::new(MetricsRegistry::new(workers)),
+        statements: Arc::new(StatementCache::new(config.statement_cache.enabled)),
+        circuit_breaker: Arc::new(CircuitBreaker::new(&config.circuit_breaker)),
+        recent_writes: Arc::new(RecentWrites::new(Duration::from_millis(
+            config.database.read_after_write_ms,
+        ))),
+        clock: clock::from_config(&config.clock),
+        tls,
+        config: Arc::new(config),
+    };
+
+    let mut handles = Vec::with_capacity(workers);
+    for worker_id in 1..workers {
+        let shared = shared.clone();
+        let unix_listener = unix_listener.as_ref().map(|listener| {
+            return listener
+                .try_clone()
+                .expect("Failed to clone unix socket listener");
+        });
+        let handle = std::thread::Builder::new()
+            .name(format!("worker-{worker_id}"))
+            .spawn(move || return worker::run(worker_id, shared, unix_listener))
+            .expect("Failed to spawn worker thread");
+        handles.push(handle);
     }
+    worker::run(0, shared, unix_listener);
+    let mut exit_code = commands::EXIT_SUCCESS;
+    for handle in handles {
+        if handle.join().is_err() {
+            logging::error!("Worker thread panicked");
+            exit_code = commands::EXIT_FAILURE;
+        }
+    }
+    return exit_code;
 }
 
-async fn handle_request(
-    pool: Arc<Pool<Postgres>>,
+pub async fn handle_request(
+    state: &Arc<AppState>,
     request: &mut [u8; 512],
     request_size: usize,
+    peer: Option<IpAddr>,
 ) -> ResponseType {
     logging::log!("Got request");
 
+    if state.config.rate_limit.enabled {
+        if let Some(response) = rate_limit::check_source_ip(
+            &state.config.rate_limit,
+            &state.rate_limiter,
+            &state.pool,
+            &state.circuit_breaker,
+            &request[..request_size],
+            peer,
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
+    if state.config.auth.enabled {
+        if let Some(response) = auth::authorize(
+            &state.pool,
+            &state.api_keys,
+            &state.circuit_breaker,
+            &request[..request_size],
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
+    if state.config.rate_limit.enabled {
+        if let Some(response) = rate_limit::check_client(
+            &state.config.rate_limit,
+            &state.rate_limiter,
+            &state.pool,
+            &state.circuit_breaker,
+            &request[..request_size],
+        )
+        .await
+        {
+            return response;
+        }
+    }
+
     if &request[0..3] == b"GET" {
         logging::log!("GET");
-        return bank_statement::get(pool, request, request_size).await;
+        return bank_statement::get(state, request, request_size).await;
     }
 
     if &request[0..4] == b"POST" {
         logging::log!("POST");
-        return transaction::post(pool, request, request_size).await;
+        if state.config.signing.enabled {
+            if let Some(response) = signing::verify(
+                &state.config.signing,
+                &state.nonces,
+                &state.pool,
+                &state.circuit_breaker,
+                state.clock.as_ref(),
+                &request[..request_size],
+            )
+            .await
+            {
+                return response;
+            }
+        }
+        return transaction::post(state, request, request_size).await;
     }
 
     return ResponseType::MethodNotAllowed;
@@ -0,0 +1,83 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+// Counters of a single worker, only that worker's runtime writes to them
+#[derive(Default)]
+pub struct WorkerMetrics {
+    requests: AtomicU64,
+    active_connections: AtomicI64,
+    request_time_us: AtomicU64,
+    responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl WorkerMetrics {
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_response(&self, status: u16, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let elapsed_us = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.request_time_us
+            .fetch_add(elapsed_us, Ordering::Relaxed);
+        let mut responses = self.responses.lock().expect("metrics poisoned");
+        *responses.entry(status).or_insert(0) += 1;
+    }
+}
+
+#[derive(Serialize)]
+struct WorkerMetricsSnapshot {
+    worker: usize,
+    requests: u64,
+    active_connections: i64,
+    request_time_us: u64,
+    responses: BTreeMap<u16, u64>,
+}
+
+pub struct MetricsRegistry {
+    workers: Vec<WorkerMetrics>,
+}
+
+impl MetricsRegistry {
+    pub fn new(workers: usize) -> Self {
+        return MetricsRegistry {
+            workers: (0..workers)
+                .map(|_| return WorkerMetrics::default())
+                .collect(),
+        };
+    }
+
+    pub fn worker(&self, worker_id: usize) -> &WorkerMetrics {
+        return &self.workers[worker_id];
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        let snapshots: Vec<WorkerMetricsSnapshot> = self
+            .workers
+            .iter()
+            .enumerate()
+            .map(|(worker, metrics)| {
+                return WorkerMetricsSnapshot {
+                    worker,
+                    requests: metrics.requests.load(Ordering::Relaxed),
+                    active_connections: metrics.active_connections.load(Ordering::Relaxed),
+                    request_time_us: metrics.request_time_us.load(Ordering::Relaxed),
+                    responses: metrics.responses.lock().expect("metrics poisoned").clone(),
+                };
+            })
+            .collect();
+        return serde_json::to_string(&snapshots);
+    }
+}
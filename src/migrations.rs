@@ -0,0 +1,111 @@
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    Pool, Postgres,
+};
+
+// Every file in db/ is embedded at build time, applied ones are tracked in _sqlx_migrations
+pub static MIGRATOR: Migrator = sqlx::migrate!("./db");
+
+fn latest_known() -> i64 {
+    return MIGRATOR
+        .iter()
+        .map(|migration| return migration.version)
+        .max()
+        .unwrap_or(0);
+}
+
+// Returns the versions not applied yet. Fails when the database was migrated by
+// a newer binary, which this one can't run against.
+pub async fn pending(pool: &Pool<Postgres>) -> Result<Vec<i64>, String> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .map_err(|e| return format!("Error acquiring connection: {}", e))?;
+    connection
+        .ensure_migrations_table()
+        .await
+        .map_err(|e| return format!("Error creating migrations table: {}", e))?;
+    let applied: Vec<i64> = connection
+        .list_applied_migrations()
+        .await
+        .map_err(|e| return format!("Error listing applied migrations: {}", e))?
+        .iter()
+        .map(|migration| return migration.version)
+        .collect();
+
+    let unknown = applied.iter().filter(|&&version| {
+        return !MIGRATOR
+            .iter()
+            .any(|migration| return migration.version == version);
+    });
+    if let Some(version) = unknown.max() {
+        return Err(format!(
+            "Database schema version {} is newer than this binary, latest known is {}",
+            version,
+            latest_known()
+        ));
+    }
+
+    return Ok(MIGRATOR
+        .iter()
+        .map(|migration| return migration.version)
+        .filter(|version| return !applied.contains(version))
+        .collect());
+}
+
+// Databases initialized by mounting db/ into the Postgres entrypoint already
+// have the initial schema but no record of it. Marks that first migration as
+// applied so the migrator doesn't try to create the tables again; the later
+// ones are idempotent and are simply run.
+async fn adopt(pool: &Pool<Postgres>) -> Result<(), String> {
+    let Some(initial) = MIGRATOR
+        .iter()
+        .min_by_key(|migration| return migration.version)
+    else {
+        return Ok(());
+    };
+    let mut connection = pool
+        .acquire()
+        .await
+        .map_err(|e| return format!("Error acquiring connection: {}", e))?;
+    connection
+        .ensure_migrations_table()
+        .await
+        .map_err(|e| return format!("Error creating migrations table: {}", e))?;
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+         SELECT $1, $2, TRUE, $3, 0
+         WHERE to_regclass('users') IS NOT NULL
+           AND NOT EXISTS (SELECT 1 FROM _sqlx_migrations)
+         ON CONFLICT (version) DO NOTHING",
+    )
+    .bind(initial.version)
+    .bind(initial.description.as_ref())
+    .bind(initial.checksum.as_ref())
+    .execute(&mut *connection)
+    .await
+    .map_err(|e| return format!("Error adopting existing schema: {}", e))?;
+    return Ok(());
+}
+
+// Applies the pending migrations and returns their versions. Concurrent
+// instances are serialized by the migrator's advisory lock.
+pub async fn run(pool: &Pool<Postgres>) -> Result<Vec<i64>, String> {
+    adopt(pool).await?;
+    let pending = pending(pool).await?;
+    MIGRATOR
+        .run(pool)
+        .await
+        .map_err(|e| return format!("Error applying migrations: {}", e))?;
+    return Ok(pending);
+}
+
+pub fn describe(version: i64) -> String {
+    return match MIGRATOR
+        .iter()
+        .find(|migration| return migration.version == version)
+    {
+        Some(migration) => format!("{} {}", version, migration.description),
+        None => version.to_string(),
+    };
+}
@@ -0,0 +1,308 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Instant};
+
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    config::{BucketConfig, RateLimitConfig},
+    logging, request,
+    responses::ResponseType,
+};
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+enum Decision {
+    Allowed,
+    // Seconds until a token is available again
+    Limited(u64),
+}
+
+fn retry_after(tokens: f64, limit: BucketConfig) -> u64 {
+    let seconds = ((1.0 - tokens) / limit.refill_per_sec).ceil();
+    return if seconds < 1.0 { 1 } else { seconds as u64 };
+}
+
+// In process token buckets, used when rate_limit.shared is disabled
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        return RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+        };
+    }
+
+    fn take_local(&self, key: String, limit: BucketConfig) -> Decision {
+        let mut buckets = self.buckets.lock().expect("rate limiter poisoned");
+        let now = Instant::now();
+        // Buckets refilled to capacity are the same as missing ones
+        if buckets.len() > 100_000 {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+                return bucket.tokens + elapsed * limit.refill_per_sec < limit.capacity;
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: limit.capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.refill_per_sec).min(limit.capacity);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return Decision::Limited(retry_after(bucket.tokens, limit));
+        }
+        bucket.tokens -= 1.0;
+        return Decision::Allowed;
+    }
+
+    pub fn clear(&self) {
+        self.buckets.lock().expect("rate limiter poisoned").clear();
+    }
+}
+
+async fn take_shared(
+    pool: &Pool<Postgres>,
+    key: String,
+    limit: BucketConfig,
+) -> Result<Decision, String> {
+    let result = sqlx::query!(
+        r#"INSERT INTO rate_limits AS r (key, tokens, allowed, updated_at) VALUES ($1, $2::float8 - 1, TRUE, now())
+        ON CONFLICT (key) DO UPDATE SET
+            tokens = LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3)
+                - CASE WHEN LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3) >= 1 THEN 1 ELSE 0 END,
+            allowed = LEAST($2, r.tokens + EXTRACT(EPOCH FROM now() - r.updated_at)::float8 * $3) >= 1,
+            updated_at = now()
+        RETURNING tokens, allowed"#,
+        key,
+        limit.capacity,
+        limit.refill_per_sec
+    )
+    .fetch_one(pool)
+    .await;
+    return match result {
+        Ok(row) if row.allowed => Ok(Decision::Allowed),
+        Ok(row) => Ok(Decision::Limited(retry_after(row.tokens, limit))),
+        Err(e) => Err(format!("Error updating rate limit {}", e)),
+    };
+}
+
+fn client_limit(config: &RateLimitConfig, client_id: i32) -> BucketConfig {
+    return match config
+        .client_overrides
+        .iter()
+        .find(|client| return client.id == client_id)
+    {
+        Some(client) => BucketConfig {
+            capacity: client.capacity,
+            refill_per_sec: client.refill_per_sec,
+        },
+        None => config.client,
+    };
+}
+
+// Unix socket connections have no peer address, so only forwarded ones are limited
+fn source_ip(config: &RateLimitConfig, request: &[u8], peer: Option<IpAddr>) -> Option<IpAddr> {
+    if config.trust_forwarded_for {
+        let forwarded_ip = request::header(request, "X-Forwarded-For")
+            .and_then(|header| return header.split(|&b| return b == b',').next())
+            .and_then(|ip| return std::str::from_utf8(request::trim(ip)).ok())
+            .and_then(|ip| return ip.parse().ok());
+        if forwarded_ip.is_some() {
+            return forwarded_ip;
+        }
+    }
+    return peer;
+}
+
+async fn take(
+    config: &RateLimitConfig,
+    limiter: &RateLimiter,
+    pool: &Pool<Postgres>,
+    breaker: &CircuitBreaker,
+    key: String,
+    limit: BucketConfig,
+) -> Option<ResponseType> {
+    // Don't take the API down with the rate limiter: requests pass when the
+    // shared buckets can't be reached, and aren't queued while the circuit is open
+    let decision = if config.shared {
+        match breaker.call(take_shared(pool, key.clone(), limit)).await {
+            Ok(Ok(decision)) => decision,
+            Ok(Err(e)) => {
+                logging::error!("{}", e);
+                Decision::Allowed
+            }
+            Err(_) => Decision::Allowed,
+        }
+    } else {
+        limiter.take_local(key.clone(), limit)
+    };
+    if let Decision::Limited(retry_after) = decision {
+        logging::log!("Rate limited {}", key);
+        return Some(ResponseType::TooManyRequests(retry_after));
+    }
+    return None;
+}
+
+// Limits by source address, before the request is authenticated. Returns None
+// when the request may proceed, or the response to send otherwise.
+pub async fn check_source_ip(
+    config: &RateLimitConfig,
+    limiter: &RateLimiter,
+    pool: &Pool<Postgres>,
+    breaker: &CircuitBreaker,
+    request: &[u8],
+    peer: Option<IpAddr>,
+) -> Option<ResponseType> {
+    let ip = source_ip(config, request, peer)?;
+    return take(
+        config,
+        limiter,
+        pool,
+        breaker,
+        format!("ip:{ip}"),
+        config.ip,
+    )
+    .await;
+}
+
+// Limits by client id. Only called once the request is authorized for that
+// client, so other callers can't use up its quota.
+pub async fn check_client(
+    config: &RateLimitConfig,
+    limiter: &RateLimiter,
+    pool: &Pool<Postgres>,
+    breaker: &CircuitBreaker,
+    request: &[u8],
+) -> Option<ResponseType> {
+    let client_id = request::client_id(request)?;
+    let limit = client_limit(config, client_id);
+    let key = format!("client:{client_id}");
+    return take(config, limiter, pool, breaker, key, limit).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::config::{CircuitBreakerConfig, ClientBucketConfig};
+
+    const LIMIT: BucketConfig = BucketConfig {
+        capacity: 2.0,
+        refill_per_sec: 20.0,
+    };
+
+    // The Retry-After of a limited request, None when it was allowed
+    fn limited(decision: Decision) -> Option<u64> {
+        return match decision {
+            Decision::Allowed => None,
+            Decision::Limited(retry_after) => Some(retry_after),
+        };
+    }
+
+    #[test]
+    fn retry_after_waits_for_a_whole_token() {
+        let slow = BucketConfig {
+            capacity: 10.0,
+            refill_per_sec: 0.5,
+        };
+        assert_eq!(retry_after(0.0, slow), 2);
+        assert_eq!(retry_after(0.4, slow), 2);
+        assert_eq!(retry_after(0.5, slow), 1);
+        // Never less than a second
+        assert_eq!(retry_after(0.99, LIMIT), 1);
+        assert_eq!(retry_after(-3.0, slow), 8);
+    }
+
+    #[test]
+    fn buckets_hold_up_to_their_capacity() {
+        let limiter = RateLimiter::new();
+        assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+        assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+        assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), Some(1));
+        // Every key has its own bucket
+        assert_eq!(limited(limiter.take_local("b".to_string(), LIMIT)), None);
+        limiter.clear();
+        assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new();
+        (0..2).for_each(|_| {
+            assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+        });
+        assert!(limited(limiter.take_local("a".to_string(), LIMIT)).is_some());
+        // One token every 50ms
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+        assert!(limited(limiter.take_local("a".to_string(), LIMIT)).is_some());
+        // Never above capacity, however long the bucket is idle
+        std::thread::sleep(Duration::from_millis(200));
+        (0..2).for_each(|_| {
+            assert_eq!(limited(limiter.take_local("a".to_string(), LIMIT)), None);
+        });
+        assert!(limited(limiter.take_local("a".to_string(), LIMIT)).is_some());
+    }
+
+    fn config() -> RateLimitConfig {
+        return RateLimitConfig {
+            enabled: true,
+            client: LIMIT,
+            client_overrides: vec![ClientBucketConfig {
+                id: 2,
+                capacity: 4.0,
+                refill_per_sec: 0.25,
+            }],
+            ..RateLimitConfig::default()
+        };
+    }
+
+    #[test]
+    fn client_overrides_replace_the_default_limit() {
+        let config = config();
+        let default = client_limit(&config, 1);
+        assert_eq!(default.capacity, 2.0);
+        assert_eq!(default.refill_per_sec, 20.0);
+        let overridden = client_limit(&config, 2);
+        assert_eq!(overridden.capacity, 4.0);
+        assert_eq!(overridden.refill_per_sec, 0.25);
+    }
+
+    #[tokio::test]
+    async fn clients_are_limited_with_their_retry_after() {
+        let config = config();
+        let limiter = RateLimiter::new();
+        // Never connected, the buckets aren't shared
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/rinha")
+            .expect("Invalid database url");
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig::default());
+        let check = |id: i32| {
+            let request = format!("GET /clientes/{id}/extrato HTTP/1.1\r\n\r\n").into_bytes();
+            let (config, limiter, pool, breaker) = (&config, &limiter, &pool, &breaker);
+            return async move {
+                return match check_client(config, limiter, pool, breaker, &request).await {
+                    None => None,
+                    Some(ResponseType::TooManyRequests(retry_after)) => Some(retry_after),
+                    Some(response) => panic!("Unexpected response {}", response.status_code()),
+                };
+            };
+        };
+        assert_eq!(check(1).await, None);
+        assert_eq!(check(1).await, None);
+        assert_eq!(check(1).await, Some(1));
+        for _ in 0..4 {
+            assert_eq!(check(2).await, None);
+        }
+        assert_eq!(check(2).await, Some(4));
+    }
+}
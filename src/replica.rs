@@ -0,0 +1,156 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{self, ReadUserResult},
+    logging,
+    user::User,
+    user_changes::Change,
+};
+
+// How long reads stay on the primary after the replica failed
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+struct Writes {
+    by_id: HashMap<i32, Instant>,
+    // Last truncate, or the last time notifications may have been missed
+    all: Option<Instant>,
+}
+
+// When each client last wrote, through this process or any other instance as
+// announced on users_changed, shared by every worker. While the announcements
+// aren't received every client may have written.
+pub struct RecentWrites {
+    window: Duration,
+    listening: AtomicBool,
+    writes: Mutex<Writes>,
+}
+
+impl RecentWrites {
+    pub fn new(window: Duration) -> RecentWrites {
+        return RecentWrites {
+            window,
+            listening: AtomicBool::new(false),
+            writes: Mutex::new(Writes {
+                by_id: HashMap::new(),
+                all: None,
+            }),
+        };
+    }
+
+    fn writes(&self) -> std::sync::MutexGuard<'_, Writes> {
+        return self.writes.lock().expect("recent writes lock poisoned");
+    }
+
+    pub fn record(&self, id: i32) {
+        let mut writes = self.writes();
+        let now = Instant::now();
+        // Writes older than the window are the same as missing ones
+        if writes.by_id.len() > 100_000 {
+            let window = self.window;
+            writes
+                .by_id
+                .retain(|_, written_at| return now.duration_since(*written_at) < window);
+        }
+        writes.by_id.insert(id, now);
+    }
+
+    pub fn apply(&self, change: &Change) {
+        match *change {
+            Change::All => self.writes().all = Some(Instant::now()),
+            Change::Account { id, .. } => self.record(id),
+        };
+    }
+
+    // Writes announced before LISTEN took effect or while disconnected were
+    // missed, so every client counts as having written just now
+    pub fn start_listening(&self) {
+        self.writes().all = Some(Instant::now());
+        self.listening.store(true, Ordering::Release);
+    }
+
+    pub fn stop_listening(&self) {
+        self.listening.store(false, Ordering::Release);
+    }
+
+    fn is_recent(&self, id: i32) -> bool {
+        if !self.listening.load(Ordering::Acquire) {
+            return true;
+        }
+        let writes = self.writes();
+        return writes
+            .all
+            .iter()
+            .chain(writes.by_id.get(&id))
+            .any(|written_at| return written_at.elapsed() < self.window);
+    }
+}
+
+// Routes statement reads to the replica, unless the client wrote recently or
+// the replica is failing
+pub struct ReadReplica {
+    pool: Arc<Pool<Postgres>>,
+    recent_writes: Arc<RecentWrites>,
+    unavailable_until: Mutex<Option<Instant>>,
+}
+
+impl ReadReplica {
+    pub fn new(pool: Pool<Postgres>, recent_writes: Arc<RecentWrites>) -> ReadReplica {
+        return ReadReplica {
+            pool: Arc::new(pool),
+            recent_writes,
+            unavailable_until: Mutex::new(None),
+        };
+    }
+
+    fn available(&self) -> bool {
+        let mut unavailable_until = self
+            .unavailable_until
+            .lock()
+            .expect("replica state lock poisoned");
+        return match *unavailable_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *unavailable_until = None;
+                logging::log!("Trying the read replica again");
+                true
+            }
+            None => true,
+        };
+    }
+
+    fn mark_unavailable(&self) {
+        let mut unavailable_until = self
+            .unavailable_until
+            .lock()
+            .expect("replica state lock poisoned");
+        *unavailable_until = Some(Instant::now() + RETRY_AFTER);
+    }
+
+    pub async fn read_user(
+        &self,
+        primary: Arc<Pool<Postgres>>,
+        id: i32,
+        user: &mut User,
+    ) -> ReadUserResult {
+        if self.recent_writes.is_recent(id) || !self.available() {
+            return db::read_user(primary, id, user).await;
+        }
+        return match db::read_user(self.pool.clone(), id, user).await {
+            ReadUserResult::InternalError(e) => {
+                logging::error!("Read replica failed, reading from the primary: {}", e);
+                self.mark_unavailable();
+                return db::read_user(primary, id, user).await;
+            }
+            result => result,
+        };
+    }
+}
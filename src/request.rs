@@ -0,0 +1,102 @@
+// Helpers to read parts of a raw HTTP request without fully parsing it
+
+pub fn header<'a>(request: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    // Skip the request line, headers end at the first empty line
+    let lines = request.split(|&b| return b == b'\n').skip(1);
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        let separator = match line.iter().position(|&b| return b == b':') {
+            Some(separator) => separator,
+            None => continue,
+        };
+        if line[..separator].eq_ignore_ascii_case(name.as_bytes()) {
+            return Some(trim(&line[separator + 1..]));
+        }
+    }
+    return None;
+}
+
+// Returns the id in /clientes/{id}/... of the request path, accepting ids with
+// more than one digit. Headers and body are never looked at.
+pub fn client_id(request: &[u8]) -> Option<i32> {
+    const PREFIX: &[u8] = b"/clientes/";
+    let (_, path) = method_and_path(request)?;
+    let start = path
+        .windows(PREFIX.len())
+        .position(|w| return w == PREFIX)?
+        + PREFIX.len();
+    let digits = path[start..]
+        .iter()
+        .take_while(|b| return b.is_ascii_digit())
+        .count();
+    if digits == 0 || path.get(start + digits) != Some(&b'/') {
+        return None;
+    }
+    return std::str::from_utf8(&path[start..start + digits])
+        .ok()?
+        .parse()
+        .ok();
+}
+
+pub fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| return !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| return !b.is_ascii_whitespace())
+        .map_or(start, |end| return end + 1);
+    return &bytes[start..end];
+}
+
+pub fn method_and_path(request: &[u8]) -> Option<(&[u8], &[u8])> {
+    let line_end = request
+        .iter()
+        .position(|&b| return b == b'\n')
+        .unwrap_or(request.len());
+    let mut parts = request[..line_end].split(|&b| return b == b' ');
+    let method = parts.next()?;
+    let path = parts.next()?;
+    return Some((method, path));
+}
+
+pub fn body(request: &[u8]) -> &[u8] {
+    for separator in [&b"\r\n\r\n"[..], &b"\n\n"[..]] {
+        if let Some(index) = request
+            .windows(separator.len())
+            .position(|w| return w == separator)
+        {
+            return &request[index + separator.len()..];
+        }
+    }
+    return &[];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_id_is_read_from_the_path() {
+        assert_eq!(
+            client_id(b"GET /clientes/42/extrato HTTP/1.1\r\n\r\n"),
+            Some(42)
+        );
+        assert_eq!(
+            client_id(b"PUT /admin/clientes/3/limite HTTP/1.1\r\n\r\n"),
+            Some(3)
+        );
+        assert_eq!(client_id(b"GET /clientes/x/extrato HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(client_id(b"GET /clientes/1 HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn client_id_ignores_headers_and_body() {
+        let request = b"POST /transacoes HTTP/1.1\r\nX-Path: /clientes/1/transacoes\r\n\r\n{\"descricao\": \"/clientes/2/\"}";
+        assert_eq!(client_id(request), None);
+    }
+}
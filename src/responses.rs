@@ -1,55 +1,430 @@
-use tokio::{
-    io::AsyncWriteExt,
-    net::TcpStream,
-};
 use crate::logging;
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-pub const METHOD_NOT_ALLOWED: &[u8] = 
-    b"HTTP/1.1 405 Method Not Allowed\nContent-Type: application/json\n\n{\"message\": \"Method Not Allowed\"}";
+pub const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-#[allow(dead_code)]
-pub const BAD_REQUEST: &[u8] =
-    b"HTTP/1.1 400 Bad Request\nContent-Type: application/json\n\n{\"message\": \"Bad Request\"}}";
+pub fn reason_phrase(status: u16) -> &'static str {
+    return match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+}
+
+// Error body, the code is stable and meant for clients to match on
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        return ApiError {
+            code,
+            message: message.into(),
+            field: None,
+        };
+    }
+
+    pub fn field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        return self;
+    }
+}
+
+fn error_code(status: u16) -> &'static str {
+    return match status {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        405 => "method_not_allowed",
+        409 => "conflict",
+        422 => "unprocessable_entity",
+        429 => "too_many_requests",
+        503 => "service_unavailable",
+        _ => "internal_error",
+    };
+}
+
+// 1xx, 204 and 304 responses can't have a body nor, mostly, a Content-Length
+fn has_body(status: u16) -> bool {
+    return !(100..200).contains(&status) && status != 204 && status != 304;
+}
 
-    pub const NOT_FOUND: &[u8] =
-    b"HTTP/1.1 404 Not Found\nContent-Type: application/json\n\n{\"message\": \"Not Found\"}";
+// HTTP/1.1 response, Date and Content-Length are filled in when it's written
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        return Response {
+            status,
+            headers: vec![
+                ("Server".to_string(), SERVER.to_string()),
+                // Every connection serves a single request
+                ("Connection".to_string(), "close".to_string()),
+            ],
+            body: Vec::new(),
+        };
+    }
+
+    // Replaces any header with the same name, CR and LF are dropped so values can't split the head
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        let name: String = name
+            .chars()
+            .filter(|c| return !c.is_ascii_control())
+            .collect();
+        let value: String = value
+            .chars()
+            .filter(|&c| return c != '\r' && c != '\n')
+            .collect();
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Date") {
+            return self;
+        }
+        self.headers
+            .retain(|(existing, _)| return !existing.eq_ignore_ascii_case(&name));
+        self.headers.push((name, value));
+        return self;
+    }
+
+    pub fn body(mut self, content_type: &str, body: Vec<u8>) -> Self {
+        self.body = body;
+        return self.header("Content-Type", content_type);
+    }
+
+    pub fn json(self, body: String) -> Self {
+        return self.body("application/json", body.into_bytes());
+    }
 
-#[allow(dead_code)]
-pub const UNPROCESSABLE_ENTITY: &[u8] =
-    b"HTTP/1.1 422 Unprocessable Entity\nContent-Type: application/json\n\n{\"message\": \"Unprocessable Entity\"}";
+    pub fn error(status: u16, error: &ApiError) -> Self {
+        let body = serde_json::to_string(error).expect("Failed to serialize error");
+        return Response::new(status).json(body);
+    }
 
-#[allow(dead_code)]
-pub const INTERNAL_SERVER_ERROR: &[u8] =
-    b"HTTP/1.1 500 Internal Server Error\nContent-Type: application/json\n\n{\"message\": \"Internal Server Error\"}";
+    // Error response with the generic code and message of its status
+    pub fn message(status: u16) -> Self {
+        return Response::error(
+            status,
+            &ApiError::new(error_code(status), reason_phrase(status)),
+        );
+    }
+
+    pub fn status(&self) -> u16 {
+        return self.status;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let date = chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT");
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nDate: {}\r\n",
+            self.status,
+            reason_phrase(self.status),
+            date
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !has_body(self.status) {
+            head.push_str("\r\n");
+            return head.into_bytes();
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        return bytes;
+    }
+}
 
-pub enum ResponseType{
+pub enum ResponseType {
     // Ok(String) is the response body
     Ok(String),
     // InternalServerError(String) is the error message to log
     InternalServerError(String),
+    // Syntactically invalid HTTP or JSON
+    BadRequest(ApiError),
+    Unauthorized,
+    Forbidden,
     NotFound,
     MethodNotAllowed,
-    UnprocessableEntity,
+    // The request raced with others and can be retried
+    Conflict(ApiError),
+    // Well formed request with values that can't be applied
+    UnprocessableEntity(ApiError),
+    // TooManyRequests(u64) is the Retry-After in seconds
+    TooManyRequests(u64),
+    // ServiceUnavailable(u64) is the Retry-After in seconds
+    ServiceUnavailable(u64),
 }
 
-pub async fn respond(mut stream: TcpStream, response: ResponseType) -> std::io::Result<()> {
-    return match response {
-        ResponseType::Ok(response_body) => {
-            let response = format!("HTTP/1.1 200 OK\nContent-Type: application/json\n\n{response_body}");
-            stream.write_all(response.as_bytes()).await
-        }
-        ResponseType::InternalServerError(error_string) => {
-            logging::error!("Internal server error {error_string}");
-            return stream.write_all(INTERNAL_SERVER_ERROR).await;
-        }
-        ResponseType::NotFound => {
-            stream.write_all(NOT_FOUND).await
-        }
-        ResponseType::MethodNotAllowed => {
-            stream.write_all(METHOD_NOT_ALLOWED).await
-        }
-        ResponseType::UnprocessableEntity => {
-            stream.write_all(UNPROCESSABLE_ENTITY).await
+impl ResponseType {
+    pub fn status_code(&self) -> u16 {
+        return match self {
+            ResponseType::Ok(_) => 200,
+            ResponseType::BadRequest(_) => 400,
+            ResponseType::Unauthorized => 401,
+            ResponseType::Forbidden => 403,
+            ResponseType::NotFound => 404,
+            ResponseType::MethodNotAllowed => 405,
+            ResponseType::Conflict(_) => 409,
+            ResponseType::UnprocessableEntity(_) => 422,
+            ResponseType::TooManyRequests(_) => 429,
+            ResponseType::InternalServerError(_) => 500,
+            ResponseType::ServiceUnavailable(_) => 503,
+        };
+    }
+
+    // Broken JSON is a bad request, valid JSON with missing fields or wrong types is unprocessable
+    pub fn from_json_error(e: serde_json::Error) -> ResponseType {
+        return match e.classify() {
+            serde_json::error::Category::Data => {
+                ResponseType::UnprocessableEntity(ApiError::new("invalid_body", e.to_string()))
+            }
+            _ => ResponseType::BadRequest(ApiError::new("malformed_json", e.to_string())),
+        };
+    }
+
+    pub fn into_response(self) -> Response {
+        let status = self.status_code();
+        return match self {
+            ResponseType::Ok(response_body) => Response::new(status).json(response_body),
+            ResponseType::InternalServerError(error_string) => {
+                logging::error!("Internal server error {error_string}");
+                return Response::message(status);
+            }
+            ResponseType::Unauthorized => {
+                Response::message(status).header("WWW-Authenticate", "Bearer")
+            }
+            ResponseType::TooManyRequests(retry_after)
+            | ResponseType::ServiceUnavailable(retry_after) => {
+                Response::message(status).header("Retry-After", &retry_after.to_string())
+            }
+            ResponseType::BadRequest(error) => {
+                logging::log!("Bad request: {} {}", error.code, error.message);
+                return Response::error(status, &error);
+            }
+            ResponseType::UnprocessableEntity(error) => {
+                logging::log!("Unprocessable entity: {} {}", error.code, error.message);
+                return Response::error(status, &error);
+            }
+            ResponseType::Conflict(error) => Response::error(status, &error),
+            ResponseType::Forbidden | ResponseType::NotFound | ResponseType::MethodNotAllowed => {
+                Response::message(status)
+            }
+        };
+    }
+}
+
+pub async fn respond<S>(stream: &mut S, response: ResponseType) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    return stream.write_all(&response.into_response().to_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Parsed {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Parsed {
+        fn header(&self, name: &str) -> Option<&str> {
+            return self
+                .headers
+                .iter()
+                .find(|(existing, _)| return existing.eq_ignore_ascii_case(name))
+                .map(|(_, value)| return value.as_str());
         }
-    };
-}
\ No newline at end of file
+    }
+
+    // Reads the response like a strict client: the head must parse with
+    // httparse and the body must be exactly Content-Length bytes
+    fn parse(bytes: &[u8]) -> Parsed {
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut response = httparse::Response::new(&mut headers);
+        let head_length = match response.parse(bytes).expect("Invalid response head") {
+            httparse::Status::Complete(head_length) => head_length,
+            httparse::Status::Partial => panic!("Incomplete response head"),
+        };
+        let headers: Vec<(String, String)> = response
+            .headers
+            .iter()
+            .map(|header| {
+                let value = std::str::from_utf8(header.value).expect("Header value isn't UTF-8");
+                return (header.name.to_string(), value.to_string());
+            })
+            .collect();
+        let content_lengths: Vec<&(String, String)> = headers
+            .iter()
+            .filter(|(name, _)| return name.eq_ignore_ascii_case("Content-Length"))
+            .collect();
+        assert!(content_lengths.len() <= 1, "Expected one Content-Length");
+        let content_length: usize = match content_lengths.first() {
+            Some((_, value)) => value.parse().expect("Invalid Content-Length"),
+            None => 0,
+        };
+        assert_eq!(bytes.len() - head_length, content_length);
+        return Parsed {
+            status: response.code.expect("Missing status code"),
+            headers,
+            body: bytes[head_length..].to_vec(),
+        };
+    }
+
+    // The lines before the empty one, without their CRLF
+    fn head_lines(bytes: &[u8]) -> Vec<&[u8]> {
+        let head_end = bytes
+            .windows(4)
+            .position(|w| return w == b"\r\n\r\n")
+            .expect("Head doesn't end with an empty line");
+        return bytes[..head_end + 2]
+            .split_inclusive(|&b| return b == b'\n')
+            .map(|line| {
+                return line
+                    .strip_suffix(b"\r\n")
+                    .expect("Line doesn't end with CRLF");
+            })
+            .collect();
+    }
+
+    #[test]
+    fn head_lines_end_with_crlf() {
+        let bytes = Response::new(200).json("{}".to_string()).to_bytes();
+        let lines = head_lines(&bytes);
+        assert_eq!(lines[0], b"HTTP/1.1 200 OK");
+        assert!(lines
+            .iter()
+            .all(|line| return !line.is_empty() && !line.contains(&b'\r')));
+    }
+
+    #[test]
+    fn content_length_counts_body_bytes() {
+        let body = "{\"descricao\":\"café\"}".to_string();
+        let parsed = parse(&Response::new(200).json(body.clone()).to_bytes());
+        assert_eq!(parsed.header("Content-Length"), Some("21"));
+        assert_eq!(parsed.body, body.as_bytes());
+        assert_eq!(parsed.header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn empty_body_has_zero_content_length() {
+        let parsed = parse(&Response::new(200).to_bytes());
+        assert_eq!(parsed.status, 200);
+        assert_eq!(parsed.header("Content-Length"), Some("0"));
+        assert!(parsed.body.is_empty());
+    }
+
+    #[test]
+    fn bodiless_statuses_have_no_content_length() {
+        [101, 204, 304].iter().for_each(|&status| {
+            let bytes = Response::new(status).json("{}".to_string()).to_bytes();
+            let parsed = parse(&bytes);
+            assert_eq!(parsed.status, status);
+            assert_eq!(parsed.header("Content-Length"), None);
+            assert!(parsed.body.is_empty());
+        });
+    }
+
+    #[test]
+    fn header_values_cant_add_headers() {
+        let bytes = Response::new(200)
+            .header("X-Test", "a\r\nSet-Cookie: session=1\r\n\r\nbody")
+            .header("X-Other\r\nSet-Cookie", "b")
+            .to_bytes();
+        let parsed = parse(&bytes);
+        assert_eq!(parsed.header("Set-Cookie"), None);
+        assert_eq!(parsed.header("X-Test"), Some("aSet-Cookie: session=1body"));
+        assert_eq!(parsed.header("X-OtherSet-Cookie"), Some("b"));
+        assert!(parsed.body.is_empty());
+    }
+
+    #[test]
+    fn content_length_and_date_cant_be_set() {
+        let bytes = Response::new(200)
+            .header("Content-Length", "100")
+            .header("date", "yesterday")
+            .json("{}".to_string())
+            .to_bytes();
+        let parsed = parse(&bytes);
+        assert_eq!(parsed.header("Content-Length"), Some("2"));
+        let dates = parsed
+            .headers
+            .iter()
+            .filter(|(name, _)| return name.eq_ignore_ascii_case("Date"))
+            .count();
+        assert_eq!(dates, 1);
+        assert_ne!(parsed.header("Date"), Some("yesterday"));
+    }
+
+    #[test]
+    fn header_replaces_existing_value() {
+        let parsed = parse(
+            &Response::new(200)
+                .header("connection", "keep-alive")
+                .to_bytes(),
+        );
+        assert_eq!(parsed.header("Connection"), Some("keep-alive"));
+        assert_eq!(parsed.headers.len(), 4);
+    }
+
+    #[test]
+    fn every_response_type_parses() {
+        let responses = [
+            ResponseType::Ok("{}".to_string()),
+            ResponseType::InternalServerError("test".to_string()),
+            ResponseType::BadRequest(ApiError::new("malformed_json", "line 1").field("body")),
+            ResponseType::Unauthorized,
+            ResponseType::Forbidden,
+            ResponseType::NotFound,
+            ResponseType::MethodNotAllowed,
+            ResponseType::Conflict(ApiError::new("concurrent_update", "retry")),
+            ResponseType::UnprocessableEntity(ApiError::new("limit_exceeded", "limit")),
+            ResponseType::TooManyRequests(3),
+            ResponseType::ServiceUnavailable(5),
+        ];
+        responses.into_iter().for_each(|response| {
+            let status = response.status_code();
+            let parsed = parse(&response.into_response().to_bytes());
+            assert_eq!(parsed.status, status);
+            assert_eq!(parsed.header("Server"), Some(SERVER));
+            assert_eq!(parsed.header("Connection"), Some("close"));
+            match status {
+                401 => assert_eq!(parsed.header("WWW-Authenticate"), Some("Bearer")),
+                429 => assert_eq!(parsed.header("Retry-After"), Some("3")),
+                503 => assert_eq!(parsed.header("Retry-After"), Some("5")),
+                _ => {}
+            };
+            if status != 200 {
+                let error: serde_json::Value =
+                    serde_json::from_slice(&parsed.body).expect("Error body isn't JSON");
+                assert!(error["code"].is_string());
+            }
+        });
+    }
+}
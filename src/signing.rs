@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    circuit_breaker::CircuitBreaker, clock::Clock, config::SigningConfig, logging, request,
+    responses::ResponseType,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Partners sign "METHOD\nPATH\nTIMESTAMP\nNONCE\nBODY" with HMAC-SHA256 and send
+// X-Partner-Id, X-Timestamp (unix seconds), X-Nonce and X-Signature (hex) headers
+
+fn new_mac(
+    secret: &[u8],
+    method: &[u8],
+    path: &[u8],
+    timestamp: &[u8],
+    nonce: &[u8],
+    body: &[u8],
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any size");
+    for part in [method, path, timestamp, nonce] {
+        mac.update(part);
+        mac.update(b"\n");
+    }
+    mac.update(body);
+    return mac;
+}
+
+// Remembers nonces seen inside the timestamp window by this process, used when
+// signing.shared is disabled. Older ones can't be replayed anyway because their
+// timestamp is rejected.
+pub struct NonceCache {
+    capacity: usize,
+    nonces: Mutex<HashMap<(String, Vec<u8>), i64>>,
+}
+
+impl NonceCache {
+    pub fn new(capacity: usize) -> Self {
+        return NonceCache {
+            capacity,
+            nonces: Mutex::new(HashMap::new()),
+        };
+    }
+
+    // Returns false if the nonce was already used or the cache is full
+    fn insert(&self, partner_id: &str, nonce: &[u8], timestamp: i64, oldest_valid: i64) -> bool {
+        let mut nonces = self.nonces.lock().expect("nonce cache poisoned");
+        if nonces.len() >= self.capacity {
+            nonces.retain(|_, seen_at| return *seen_at >= oldest_valid);
+            if nonces.len() >= self.capacity {
+                logging::error!("Nonce cache is full, rejecting signed request");
+                return false;
+            }
+        }
+        let key = (partner_id.to_string(), nonce.to_vec());
+        if nonces.contains_key(&key) {
+            return false;
+        }
+        nonces.insert(key, timestamp);
+        return true;
+    }
+}
+
+// Returns whether the nonce is new. A nonce is taken again once it expired.
+async fn insert_shared(
+    pool: &Pool<Postgres>,
+    partner_id: &str,
+    nonce: &[u8],
+    expires_at: i64,
+    now: i64,
+) -> Result<bool, String> {
+    let result = sqlx::query!(
+        "INSERT INTO signing_nonces AS n (partner_id, nonce, expires_at) VALUES ($1, $2, $3)
+        ON CONFLICT (partner_id, nonce) DO UPDATE SET expires_at = $3 WHERE n.expires_at < $4",
+        partner_id,
+        nonce,
+        expires_at,
+        now
+    )
+    .execute(pool)
+    .await;
+    return match result {
+        Ok(result) => Ok(result.rows_affected() == 1),
+        Err(e) => Err(format!("Error storing signing nonce {}", e)),
+    };
+}
+
+// Deletes the shared nonces that expired, for as long as the process runs
+pub async fn expire_nonces(pool: Arc<Pool<Postgres>>, clock: Arc<dyn Clock>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let result = sqlx::query!(
+            "DELETE FROM signing_nonces WHERE expires_at < $1",
+            clock.now().timestamp()
+        )
+        .execute(pool.as_ref())
+        .await;
+        match result {
+            Ok(result) => logging::log!("Deleted {} expired nonces", result.rows_affected()),
+            Err(e) => logging::error!("Error deleting expired nonces {}", e),
+        };
+    }
+}
+
+// Returns None when the signature is valid, or the response to send otherwise
+pub async fn verify(
+    config: &SigningConfig,
+    nonces: &NonceCache,
+    pool: &Pool<Postgres>,
+    breaker: &CircuitBreaker,
+    clock: &dyn Clock,
+    request: &[u8],
+) -> Option<ResponseType> {
+    let (method, path) = match request::method_and_path(request) {
+        Some(method_and_path) => method_and_path,
+        None => return Some(ResponseType::Unauthorized),
+    };
+    let headers = (
+        request::header(request, "X-Partner-Id"),
+        request::header(request, "X-Timestamp"),
+        request::header(request, "X-Nonce"),
+        request::header(request, "X-Signature"),
+    );
+    let (partner_id, timestamp, nonce, signature) = match headers {
+        (Some(partner_id), Some(timestamp), Some(nonce), Some(signature)) if !nonce.is_empty() => {
+            (partner_id, timestamp, nonce, signature)
+        }
+        _ => {
+            logging::log!("Missing signature headers");
+            return Some(ResponseType::Unauthorized);
+        }
+    };
+
+    let partner = match config
+        .partners
+        .iter()
+        .find(|partner| return partner.id.as_bytes() == partner_id)
+    {
+        Some(partner) => partner,
+        None => {
+            logging::log!("Unknown partner {}", String::from_utf8_lossy(partner_id));
+            return Some(ResponseType::Unauthorized);
+        }
+    };
+
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return Some(ResponseType::Unauthorized),
+    };
+    let mac = new_mac(
+        partner.secret.as_bytes(),
+        method,
+        path,
+        timestamp,
+        nonce,
+        request::body(request),
+    );
+    if mac.verify_slice(&signature).is_err() {
+        logging::log!("Invalid signature for partner {}", partner.id);
+        return Some(ResponseType::Unauthorized);
+    }
+
+    let timestamp: i64 = match std::str::from_utf8(timestamp)
+        .ok()
+        .and_then(|t| return t.parse().ok())
+    {
+        Some(timestamp) => timestamp,
+        None => return Some(ResponseType::Unauthorized),
+    };
+    let now = clock.now().timestamp();
+    let max_skew = i64::try_from(config.max_skew_secs).unwrap_or(i64::MAX);
+    if timestamp.abs_diff(now) > config.max_skew_secs {
+        logging::log!(
+            "Signed request from {} outside of the timestamp window",
+            partner.id
+        );
+        return Some(ResponseType::Unauthorized);
+    }
+    let fresh = if config.shared {
+        let expires_at = timestamp.saturating_add(max_skew);
+        let inserted = breaker
+            .call(insert_shared(pool, &partner.id, nonce, expires_at, now))
+            .await;
+        match inserted {
+            Ok(Ok(fresh)) => fresh,
+            Ok(Err(e)) => return Some(ResponseType::InternalServerError(e)),
+            Err(retry_after) => return Some(ResponseType::ServiceUnavailable(retry_after)),
+        }
+    } else {
+        nonces.insert(&partner.id, nonce, timestamp, now.saturating_sub(max_skew))
+    };
+    if !fresh {
+        logging::log!("Replayed nonce from partner {}", partner.id);
+        return Some(ResponseType::Unauthorized);
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::{
+        clock::{self, FixedClock},
+        config::{CircuitBreakerConfig, PartnerKey},
+    };
+
+    const NOW: i64 = 1_706_000_000;
+    const BODY: &str = r#"{"valor":10,"tipo":"c","descricao":"abc"}"#;
+
+    fn config() -> SigningConfig {
+        return SigningConfig {
+            enabled: true,
+            max_skew_secs: 300,
+            shared: false,
+            nonce_cache_size: 100,
+            partners: vec![PartnerKey {
+                id: "partner".to_string(),
+                secret: "secret".to_string(),
+            }],
+        };
+    }
+
+    // Transaction request signed with secret, with these header values
+    fn signed_request(partner_id: &str, secret: &str, timestamp: i64, nonce: &str) -> Vec<u8> {
+        let path = "/clientes/1/transacoes";
+        let timestamp = timestamp.to_string();
+        let mac = new_mac(
+            secret.as_bytes(),
+            b"POST",
+            path.as_bytes(),
+            timestamp.as_bytes(),
+            nonce.as_bytes(),
+            BODY.as_bytes(),
+        );
+        let signature = hex::encode(mac.finalize().into_bytes());
+        return format!(
+            "POST {path} HTTP/1.1\r\nX-Partner-Id: {partner_id}\r\nX-Timestamp: {timestamp}\r\nX-Nonce: {nonce}\r\nX-Signature: {signature}\r\nContent-Length: {}\r\n\r\n{BODY}",
+            BODY.len()
+        )
+        .into_bytes();
+    }
+
+    struct Verifier {
+        config: SigningConfig,
+        nonces: NonceCache,
+        pool: Pool<Postgres>,
+        breaker: CircuitBreaker,
+        clock: FixedClock,
+    }
+
+    impl Verifier {
+        fn new() -> Verifier {
+            let config = config();
+            let now = clock::timestamp::parse("2024-01-23T08:53:20Z").expect("Invalid timestamp");
+            assert_eq!(now.timestamp(), NOW);
+            return Verifier {
+                nonces: NonceCache::new(config.nonce_cache_size),
+                config,
+                // Never connected, the nonces aren't shared
+                pool: PgPoolOptions::new()
+                    .connect_lazy("postgres://localhost/rinha")
+                    .expect("Invalid database url"),
+                breaker: CircuitBreaker::new(&CircuitBreakerConfig::default()),
+                clock: FixedClock(now),
+            };
+        }
+
+        // Whether the request is accepted, panics on anything but a 401 otherwise
+        async fn accepts(&self, request: &[u8]) -> bool {
+            let response = verify(
+                &self.config,
+                &self.nonces,
+                &self.pool,
+                &self.breaker,
+                &self.clock,
+                request,
+            )
+            .await;
+            return match response {
+                None => true,
+                Some(ResponseType::Unauthorized) => false,
+                Some(response) => panic!("Unexpected response {}", response.status_code()),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_signatures_are_accepted() {
+        let verifier = Verifier::new();
+        assert!(
+            verifier
+                .accepts(&signed_request("partner", "secret", NOW, "n1"))
+                .await
+        );
+        assert!(
+            verifier
+                .accepts(&signed_request("partner", "secret", NOW, "n2"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_signatures_are_rejected() {
+        let verifier = Verifier::new();
+        let wrong_secret = signed_request("partner", "other", NOW, "n1");
+        assert!(!verifier.accepts(&wrong_secret).await);
+        let unknown_partner = signed_request("other", "secret", NOW, "n1");
+        assert!(!verifier.accepts(&unknown_partner).await);
+
+        let request = String::from_utf8(signed_request("partner", "secret", NOW, "n1"))
+            .expect("Request isn't UTF-8");
+        let tampered = request.replace(r#""valor":10"#, r#""valor":99"#);
+        assert!(!verifier.accepts(tampered.as_bytes()).await);
+        let other_path = request.replace("/clientes/1/", "/clientes/2/");
+        assert!(!verifier.accepts(other_path.as_bytes()).await);
+        let signature_start = request.find("X-Signature: ").expect("No signature") + 13;
+        let mut not_hex = request.clone();
+        not_hex.replace_range(signature_start..signature_start + 2, "zz");
+        assert!(!verifier.accepts(not_hex.as_bytes()).await);
+        let unsigned = request.replace("X-Signature", "X-Other");
+        assert!(!verifier.accepts(unsigned.as_bytes()).await);
+
+        // The rejected requests didn't use up the nonce
+        assert!(verifier.accepts(request.as_bytes()).await);
+    }
+
+    #[tokio::test]
+    async fn timestamps_must_be_within_the_skew() {
+        let verifier = Verifier::new();
+        assert!(
+            verifier
+                .accepts(&signed_request("partner", "secret", NOW - 300, "n1"))
+                .await
+        );
+        assert!(
+            verifier
+                .accepts(&signed_request("partner", "secret", NOW + 300, "n2"))
+                .await
+        );
+        assert!(
+            !verifier
+                .accepts(&signed_request("partner", "secret", NOW - 301, "n3"))
+                .await
+        );
+        assert!(
+            !verifier
+                .accepts(&signed_request("partner", "secret", NOW + 301, "n4"))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn nonces_cant_be_replayed() {
+        let verifier = Verifier::new();
+        let request = signed_request("partner", "secret", NOW, "n1");
+        assert!(verifier.accepts(&request).await);
+        assert!(!verifier.accepts(&request).await);
+        // Even with another timestamp
+        assert!(
+            !verifier
+                .accepts(&signed_request("partner", "secret", NOW - 1, "n1"))
+                .await
+        );
+    }
+
+    #[test]
+    fn full_nonce_cache_drops_expired_nonces() {
+        let nonces = NonceCache::new(2);
+        assert!(nonces.insert("partner", b"n1", NOW - 400, NOW - 300));
+        assert!(nonces.insert("partner", b"n2", NOW, NOW - 300));
+        // n1 is outside of the window, its slot is reused
+        assert!(nonces.insert("partner", b"n3", NOW, NOW - 300));
+        assert!(!nonces.insert("partner", b"n2", NOW, NOW - 300));
+        // Full with valid nonces, new ones are refused rather than forgotten
+        assert!(!nonces.insert("partner", b"n4", NOW, NOW - 300));
+        // The same nonce of another partner is a different one
+        let nonces = NonceCache::new(2);
+        assert!(nonces.insert("partner", b"n1", NOW, NOW - 300));
+        assert!(nonces.insert("other", b"n1", NOW, NOW - 300));
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    account_queue::AccountQueues,
+    auth::ApiKeyCache,
+    circuit_breaker::CircuitBreaker,
+    clock::Clock,
+    config::Config,
+    group_commit::GroupCommit,
+    metrics::{MetricsRegistry, WorkerMetrics},
+    rate_limit::RateLimiter,
+    replica::{ReadReplica, RecentWrites},
+    signing::NonceCache,
+    statement_cache::StatementCache,
+};
+
+// Everything a request handler may need. Each worker has its own pools, account
+// queues and group commit, the rest is shared by every worker of the process.
+pub struct AppState {
+    pub worker_id: usize,
+    pub pool: Arc<Pool<Postgres>>,
+    pub account_queues: AccountQueues,
+    pub group_commit: GroupCommit,
+    pub config: Arc<Config>,
+    pub api_keys: Arc<ApiKeyCache>,
+    pub nonces: Arc<NonceCache>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub metrics: Arc<MetricsRegistry>,
+    pub statements: Arc<StatementCache>,
+    pub circuit_breaker: Arc<CircuitBreaker>,
+    pub recent_writes: Arc<RecentWrites>,
+    pub replica: Option<ReadReplica>,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl AppState {
+    pub fn worker_metrics(&self) -> &WorkerMetrics {
+        return self.metrics.worker(self.worker_id);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{bank_statement, logging, user::User, user_changes::Change};
+
+// A statement without its data_extrato, which is filled in on every request
+#[derive(Clone)]
+pub struct CachedStatement {
+    pub balance: i32,
+    pub balance_limit: i32,
+    // ultimas_transacoes, already serialized
+    pub transactions: String,
+}
+
+// The statement is None once a newer version was announced, so a slow read of
+// an older row can't bring it back
+struct Entry {
+    version: i64,
+    statement: Option<CachedStatement>,
+}
+
+// Statements per client, written through by this process' updates and
+// invalidated by the notifications of every instance's. Nothing is served
+// while the notifications aren't being received.
+pub struct StatementCache {
+    enabled: bool,
+    listening: AtomicBool,
+    entries: Mutex<HashMap<i32, Entry>>,
+}
+
+impl StatementCache {
+    pub fn new(enabled: bool) -> StatementCache {
+        return StatementCache {
+            enabled,
+            listening: AtomicBool::new(false),
+            entries: Mutex::new(HashMap::new()),
+        };
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<i32, Entry>> {
+        return self.entries.lock().expect("statement cache lock poisoned");
+    }
+
+    pub fn get(&self, id: i32) -> Option<CachedStatement> {
+        if !self.enabled || !self.listening.load(Ordering::Acquire) {
+            return None;
+        }
+        return self
+            .entries()
+            .get(&id)
+            .and_then(|entry| return entry.statement.clone());
+    }
+
+    // Keeps the user's statement unless a newer version is already known. Nothing
+    // is kept while not listening, a change missed then could never evict it.
+    pub fn store(&self, user: &User) {
+        if !self.enabled || !self.listening.load(Ordering::Acquire) {
+            return;
+        }
+        let statement = match bank_statement::cached_statement(user) {
+            Ok(statement) => statement,
+            Err(e) => {
+                logging::error!("Not caching the statement of user {}: {}", user.id, e);
+                return;
+            }
+        };
+        let mut entries = self.entries();
+        let newer = match entries.get(&user.id) {
+            Some(entry) => {
+                user.version > entry.version
+                    || (user.version == entry.version && entry.statement.is_none())
+            }
+            None => true,
+        };
+        if newer {
+            entries.insert(
+                user.id,
+                Entry {
+                    version: user.version,
+                    statement: Some(statement),
+                },
+            );
+        }
+    }
+
+    fn invalidate(&self, id: i32, version: i64) {
+        let mut entries = self.entries();
+        if let Some(entry) = entries.get(&id) {
+            if entry.version >= version {
+                return;
+            }
+        }
+        entries.insert(
+            id,
+            Entry {
+                version,
+                statement: None,
+            },
+        );
+    }
+
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+
+    pub fn apply(&self, change: &Change) {
+        match *change {
+            Change::All => self.clear(),
+            Change::Account {
+                id,
+                version: Some(version),
+            } => self.invalidate(id, version),
+            // Deleted accounts come back with version 0, so nothing is remembered
+            Change::Account { id, version: None } => {
+                self.entries().remove(&id);
+            }
+        };
+    }
+
+    // Statements stored before LISTEN took effect may have missed a change
+    pub fn start_listening(&self) {
+        self.clear();
+        self.listening.store(true, Ordering::Release);
+    }
+
+    // Notifications missed while disconnected can't be replayed, so the cache
+    // is emptied and unused until listening again
+    pub fn stop_listening(&self) {
+        self.listening.store(false, Ordering::Release);
+        self.clear();
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use crate::{config::TlsConfig, logging};
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file =
+        File::open(path).map_err(|e| return format!("failed to open {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            return format!("failed to read certificates from {}: {}", path.display(), e);
+        })?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+    return Ok(certs);
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file =
+        File::open(path).map_err(|e| return format!("failed to open {}: {}", path.display(), e))?;
+    return rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| return format!("failed to read private key from {}: {}", path.display(), e))?
+        .ok_or_else(|| return format!("no private key found in {}", path.display()));
+}
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, String> {
+    let certs = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| return format!("invalid TLS protocol versions: {}", e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| return format!("invalid certificate or key: {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    return Ok(TlsAcceptor::from(Arc::new(config)));
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    return std::fs::metadata(path)
+        .and_then(|m| return m.modified())
+        .ok();
+}
+
+// Holds the current acceptor, replaced whenever the certificate or key file changes
+pub struct TlsReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsReloader {
+    pub fn new(config: &TlsConfig) -> Result<Self, String> {
+        let cert_path = config.cert_path.clone().unwrap_or_default();
+        let key_path = config.key_path.clone().unwrap_or_default();
+        let acceptor = load_acceptor(&cert_path, &key_path)?;
+        return Ok(TlsReloader {
+            cert_path,
+            key_path,
+            acceptor: RwLock::new(acceptor),
+        });
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        return self.acceptor.read().expect("tls acceptor poisoned").clone();
+    }
+
+    // Polls the files' modification times, a bad certificate keeps the previous one in use
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut last_modified = (modified_at(&self.cert_path), modified_at(&self.key_path));
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = (modified_at(&self.cert_path), modified_at(&self.key_path));
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            match load_acceptor(&self.cert_path, &self.key_path) {
+                Ok(acceptor) => {
+                    *self.acceptor.write().expect("tls acceptor poisoned") = acceptor;
+                    logging::log!("Reloaded TLS certificate {}", self.cert_path.display());
+                }
+                Err(e) => {
+                    logging::error!("Failed to reload TLS certificate: {}", e);
+                }
+            };
+        }
+    }
+}
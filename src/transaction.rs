@@ -1,27 +1,123 @@
-use std::sync::Arc;
-
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
+use unicode_normalization::UnicodeNormalization;
 
 use crate::{
-    db::{update_user_with_transaction, UpdateUserResult},
-    logging,
-    responses::ResponseType,
+    clock,
+    config::TransactionsConfig,
+    db::{self, UpdateUserResult},
+    logging, request,
+    responses::{ApiError, ResponseType},
+    state::AppState,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 struct TransactionRequest {
-    valor: i32,
+    // Kept as a number so floats like 1.0 can be told apart from bad types
+    valor: serde_json::Number,
     descricao: String,
     tipo: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+pub enum ValidationError {
+    InvalidTransactionKind(String),
+    NonIntegerValue,
+    NonPositiveValue,
+    ValueOutOfRange,
+    // InvalidDescription(usize) is the maximum length in characters
+    InvalidDescription(usize),
+}
+
+impl ValidationError {
+    pub fn error(&self) -> ApiError {
+        return match self {
+            ValidationError::InvalidTransactionKind(tipo) => ApiError::new(
+                "invalid_transaction_kind",
+                format!("Invalid tipo {:?}, expected \"c\" or \"d\"", tipo),
+            )
+            .field("tipo"),
+            ValidationError::NonIntegerValue => {
+                ApiError::new("non_integer_value", "valor must be an integer").field("valor")
+            }
+            ValidationError::NonPositiveValue => {
+                ApiError::new("non_positive_value", "valor must be positive").field("valor")
+            }
+            ValidationError::ValueOutOfRange => ApiError::new(
+                "value_out_of_range",
+                format!("valor must be at most {}", i32::MAX),
+            )
+            .field("valor"),
+            ValidationError::InvalidDescription(max_length) => ApiError::new(
+                "invalid_description",
+                format!("descricao must have between 1 and {max_length} characters"),
+            )
+            .field("descricao"),
+        };
+    }
+}
+
+pub fn validate_kind(tipo: &str) -> Result<(), ValidationError> {
+    return match tipo {
+        "c" | "d" => Ok(()),
+        tipo => Err(ValidationError::InvalidTransactionKind(tipo.to_string())),
+    };
+}
+
+// Returns the NFC form of the description, so "e" followed by a combining accent
+// counts and is stored as a single "é"
+pub fn normalize_description(
+    descricao: &str,
+    max_length: usize,
+) -> Result<String, ValidationError> {
+    let descricao: String = descricao.nfc().collect();
+    let length = descricao.chars().count();
+    if length == 0 || length > max_length {
+        return Err(ValidationError::InvalidDescription(max_length));
+    }
+    return Ok(descricao);
+}
+
+fn validate_value(valor: &serde_json::Number) -> Result<i32, ValidationError> {
+    if valor.is_f64() {
+        return Err(ValidationError::NonIntegerValue);
+    }
+    let valor = match valor.as_i64() {
+        Some(valor) => valor,
+        // Only integers above i64::MAX don't fit
+        None => return Err(ValidationError::ValueOutOfRange),
+    };
+    if valor <= 0 {
+        return Err(ValidationError::NonPositiveValue);
+    }
+    return i32::try_from(valor).map_err(|_| return ValidationError::ValueOutOfRange);
+}
+
+impl TransactionRequest {
+    fn validate(
+        self,
+        config: &TransactionsConfig,
+        realizada_em: DateTime<Utc>,
+    ) -> Result<Transaction, ValidationError> {
+        let valor = validate_value(&self.valor)?;
+        validate_kind(&self.tipo)?;
+        let descricao = normalize_description(&self.descricao, config.max_description_length)?;
+        return Ok(Transaction {
+            valor,
+            descricao,
+            tipo: self.tipo,
+            realizada_em,
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub valor: i32,
     pub descricao: String,
     pub tipo: String,
-    pub realizada_em: String,
+    #[serde(with = "clock::timestamp")]
+    pub realizada_em: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -36,7 +132,7 @@ impl Default for Transaction {
             valor: 0,
             descricao: String::new(),
             tipo: String::new(),
-            realizada_em: String::new(),
+            realizada_em: DateTime::<Utc>::default(),
         };
     }
 }
@@ -46,61 +142,63 @@ impl Default for Transaction {
 // 16 + 38 = 54 for the minimum size of a valid request
 const MINIMUM_POST_REQUEST_SIZE: usize = 54;
 
-pub async fn post(
-    pool: Arc<Pool<Postgres>>,
-    request: &mut [u8; 512],
-    request_size: usize,
-) -> ResponseType {
+pub async fn post(state: &AppState, request: &mut [u8; 512], request_size: usize) -> ResponseType {
+    let config = &state.config;
     logging::log!("Request size: {}", request_size);
     logging::log!(
         "Request: {}",
         logging::into_log_json(&request[..request_size])
     );
-    if request_size < MINIMUM_POST_REQUEST_SIZE {
-        logging::error!("Request too small: {}", String::from_utf8_lossy(request));
-        return ResponseType::UnprocessableEntity;
-    }
-
-    let id = match get_id(request) {
-        Some(id) => id,
-        None => {
-            logging::log!("Id not found in request");
-            return ResponseType::UnprocessableEntity;
-        }
-    };
-
-    let transaction = match get_body(request) {
-        Some(transaction) => transaction,
-        None => {
-            return ResponseType::UnprocessableEntity;
-        }
+    let parsed = parse_request(
+        &config.transactions,
+        state.clock.now(),
+        &request[..request_size],
+    );
+    let (id, transaction) = match parsed {
+        Ok(parsed) => parsed,
+        Err(response) => return response,
     };
 
-    let current_datetime = chrono::Local::now();
-    let formatted_datetime = current_datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-
-    let transaction = Transaction {
-        valor: transaction.valor,
-        descricao: transaction.descricao,
-        tipo: transaction.tipo,
-        realizada_em: formatted_datetime,
+    let applied = state
+        .circuit_breaker
+        .call(db::apply_transaction(
+            state.pool.clone(),
+            &state.account_queues,
+            &state.group_commit,
+            &config.storage,
+            id,
+            &transaction,
+        ))
+        .await;
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(retry_after) => return ResponseType::ServiceUnavailable(retry_after),
     };
-
-    let user = match update_user_with_transaction(pool, id, &transaction).await {
+    let user = match applied {
         UpdateUserResult::Ok(user) => user,
-        UpdateUserResult::Unprocessable(err) => {
-            logging::log!("Unprocessable entity: {}", err);
-            return ResponseType::UnprocessableEntity;
+        UpdateUserResult::Unprocessable(error) => {
+            return ResponseType::UnprocessableEntity(error);
         }
         UpdateUserResult::NotFound => {
             logging::log!("User {} not found on update", id);
             return ResponseType::NotFound;
         }
+        UpdateUserResult::Conflict => {
+            return ResponseType::Conflict(ApiError::new(
+                "concurrent_update",
+                "The account changed concurrently too many times, retry the transaction",
+            ));
+        }
         UpdateUserResult::InternalError(error) => {
             return ResponseType::InternalServerError(error);
         }
     };
 
+    state.statements.store(&user);
+    if state.replica.is_some() {
+        state.recent_writes.record(id);
+    }
+
     let response = PostTransactionResponse {
         limite: user.balance_limit,
         saldo: user.balance,
@@ -125,61 +223,59 @@ pub async fn post(
     return ResponseType::Ok(response_str);
 }
 
-fn get_id(request: &[u8]) -> Option<i32> {
-    let first_separator = request[14];
-    let maybe_id = request[15];
-    let second_separator = request[16];
-    if first_separator != b'/' || second_separator != b'/' || !maybe_id.is_ascii_digit() {
-        return None;
+// Returns the client id and the validated transaction, or the 400 or 422 to send
+fn parse_request(
+    config: &TransactionsConfig,
+    realizada_em: DateTime<Utc>,
+    request: &[u8],
+) -> Result<(i32, Transaction), ResponseType> {
+    if request.len() < MINIMUM_POST_REQUEST_SIZE {
+        logging::error!("Request too small: {}", String::from_utf8_lossy(request));
+        return Err(ResponseType::BadRequest(ApiError::new(
+            "invalid_request",
+            "Request is too short to hold a transaction",
+        )));
     }
-    let id = i32::from(maybe_id);
-    let zero_ascii: i32 = i32::from(b'0');
-    return Some(id - zero_ascii);
-}
 
-fn get_body(request: &[u8]) -> Option<TransactionRequest> {
-    let mut request_iter = request.iter();
-    let body_start = match request_iter.position(|&x| return x == b'{') {
-        Some(index) => index,
-        None => {
-            logging::log!("Failed to find start of json");
-            return None;
-        }
-    };
-    logging::log!("Body start: {}", body_start);
-    let mut body_end = match request_iter.position(|&x| return x == b'}') {
-        Some(index) => index,
+    let id = match request::client_id(request) {
+        Some(id) => id,
         None => {
-            logging::log!("Failed to find end of json");
-            return None;
+            logging::log!("Id not found in request");
+            return Err(ResponseType::BadRequest(
+                ApiError::new("invalid_id", "Client id must be a number").field("id"),
+            ));
         }
     };
-    body_end = body_end + body_start + 2;
-    logging::log!("Body end: {}", body_end);
-
-    let transaction =
-        match serde_json::from_slice::<TransactionRequest>(&request[body_start..body_end]) {
-            Ok(transaction) => transaction,
-            Err(_) => {
-                logging::log!(
-                    "Failed to parse body from request {}",
-                    logging::into_log_json(&request[body_start..body_end])
-                );
-                return None;
-            }
-        };
 
-    return Some(transaction);
+    let transaction = get_body(request)?
+        .validate(config, realizada_em)
+        .map_err(|error| return ResponseType::UnprocessableEntity(error.error()))?;
+    return Ok((id, transaction));
+}
+
+fn get_body(request: &[u8]) -> Result<TransactionRequest, ResponseType> {
+    let body = request::trim(request::body(request));
+    if body.is_empty() {
+        logging::log!("Request has no body");
+        return Err(ResponseType::BadRequest(ApiError::new(
+            "missing_body",
+            "Body must be a JSON object",
+        )));
+    }
+
+    return serde_json::from_slice::<TransactionRequest>(body).map_err(|e| {
+        logging::log!(
+            "Failed to parse body from request {}",
+            logging::into_log_json(body)
+        );
+        return ResponseType::from_json_error(e);
+    });
 }
 
+// Descriptions are stored as UTF-8, so the encoded size grows with
+// transactions.max_description_length and with non-ASCII characters
 pub fn encode_transactions(transactions: &[Transaction; 10]) -> Vec<u8> {
-    let encoded_transactions =
-        bincode::serialize(transactions).expect("Failed to encode transactions");
-    assert!(
-        encoded_transactions.len() < 1000,
-        "Encoded transactions too large"
-    );
-    return encoded_transactions;
+    return bincode::serialize(transactions).expect("Failed to encode transactions");
 }
 
 pub fn decode_transactions(
@@ -191,5 +287,251 @@ pub fn decode_transactions(
     }
     let encoded_transactions = encoded_transactions.unwrap();
     *transactions =
-        bincode::deserialize(&encoded_transactions).expect("Failed to decode transactions");
+        try_decode_transactions(&encoded_transactions).expect("Failed to decode transactions");
+}
+
+pub fn try_decode_transactions(encoded_transactions: &[u8]) -> Result<[Transaction; 10], String> {
+    return bincode::deserialize(encoded_transactions).map_err(|e| return e.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, FixedClock},
+        user::User,
+    };
+
+    fn post_request(path: &str, body: &str) -> Vec<u8> {
+        return format!(
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes();
+    }
+
+    fn parse(request: &[u8]) -> Result<(i32, Transaction), ResponseType> {
+        return parse_request(&TransactionsConfig::default(), Utc::now(), request);
+    }
+
+    // Status and error code of a refused request
+    fn refusal(request: &[u8]) -> (u16, &'static str) {
+        let response = match parse(request) {
+            Ok(_) => panic!("Request was accepted"),
+            Err(response) => response,
+        };
+        let code = match &response {
+            ResponseType::BadRequest(error) | ResponseType::UnprocessableEntity(error) => {
+                error.code
+            }
+            _ => "",
+        };
+        return (response.status_code(), code);
+    }
+
+    fn refusal_of_body(body: &str) -> (u16, &'static str) {
+        return refusal(&post_request("/clientes/1/transacoes", body));
+    }
+
+    #[test]
+    fn accepts_valid_transaction() {
+        let body = r#"{"valor": 1000, "tipo": "d", "descricao": "descricao"}"#;
+        let (id, transaction) = parse(&post_request("/clientes/12/transacoes", body))
+            .unwrap_or_else(|_| panic!("Request was refused"));
+        assert_eq!(id, 12);
+        assert_eq!(transaction.valor, 1000);
+        assert_eq!(transaction.tipo, "d");
+        assert_eq!(transaction.descricao, "descricao");
+    }
+
+    #[test]
+    fn short_request_is_bad_request() {
+        assert_eq!(
+            refusal(b"POST /clientes/1/transacoes HTTP/1.1\r\n\r\n{}"),
+            (400, "invalid_request")
+        );
+    }
+
+    #[test]
+    fn non_numeric_id_is_bad_request() {
+        let body = r#"{"valor": 1, "tipo": "c", "descricao": "x"}"#;
+        assert_eq!(
+            refusal(&post_request("/clientes/abc/transacoes", body)),
+            (400, "invalid_id")
+        );
+    }
+
+    #[test]
+    fn missing_body_is_bad_request() {
+        let request =
+            b"POST /clientes/1/transacoes HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(refusal(request), (400, "missing_body"));
+    }
+
+    #[test]
+    fn broken_json_is_bad_request() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c", "descricao": "x""#),
+            (400, "malformed_json")
+        );
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c" "descricao": "x"}"#),
+            (400, "malformed_json")
+        );
+    }
+
+    #[test]
+    fn missing_field_is_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c"}"#),
+            (422, "invalid_body")
+        );
+    }
+
+    #[test]
+    fn wrong_field_type_is_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": "1", "tipo": "c", "descricao": "x"}"#),
+            (422, "invalid_body")
+        );
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c", "descricao": null}"#),
+            (422, "invalid_body")
+        );
+    }
+
+    #[test]
+    fn float_value_is_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1.5, "tipo": "c", "descricao": "x"}"#),
+            (422, "non_integer_value")
+        );
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1.0, "tipo": "c", "descricao": "x"}"#),
+            (422, "non_integer_value")
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 0, "tipo": "c", "descricao": "x"}"#),
+            (422, "non_positive_value")
+        );
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 2147483648, "tipo": "c", "descricao": "x"}"#),
+            (422, "value_out_of_range")
+        );
+    }
+
+    #[test]
+    fn bad_kind_is_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "x", "descricao": "x"}"#),
+            (422, "invalid_transaction_kind")
+        );
+    }
+
+    #[test]
+    fn long_description_is_unprocessable() {
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c", "descricao": "12345678901"}"#),
+            (422, "invalid_description")
+        );
+        assert_eq!(
+            refusal_of_body(r#"{"valor": 1, "tipo": "c", "descricao": ""}"#),
+            (422, "invalid_description")
+        );
+    }
+
+    #[test]
+    fn exceeding_the_limit_is_unprocessable() {
+        let mut user = User {
+            id: 1,
+            balance_limit: 1000,
+            balance: 0,
+            transactions_count: 0,
+            last_transaction: 0,
+            transactions: Default::default(),
+            version: 0,
+        };
+        let body = r#"{"valor": 1001, "tipo": "d", "descricao": "x"}"#;
+        let (_, transaction) = parse(&post_request("/clientes/1/transacoes", body))
+            .unwrap_or_else(|_| panic!("Request was refused"));
+        let error = match user.compute_transaction(&transaction).error() {
+            Some(error) => error,
+            None => panic!("Debit over the limit was applied"),
+        };
+        assert_eq!(error.code, "limit_exceeded");
+        assert_eq!(ResponseType::UnprocessableEntity(error).status_code(), 422);
+        assert_eq!(user.balance, 0);
+    }
+
+    #[test]
+    fn decomposed_accents_count_as_one_character() {
+        let decomposed = "e\u{301}".repeat(10);
+        assert_eq!(decomposed.chars().count(), 20);
+        let normalized = match normalize_description(&decomposed, 10) {
+            Ok(normalized) => normalized,
+            Err(_) => panic!("Ten accented characters were refused"),
+        };
+        assert_eq!(normalized, "\u{e9}".repeat(10));
+        assert!(normalize_description(&"e\u{301}".repeat(11), 10).is_err());
+
+        let body = format!(
+            r#"{{"valor": 1, "tipo": "c", "descricao": "{}"}}"#,
+            "e\\u0301".repeat(10)
+        );
+        let (_, transaction) = parse(&post_request("/clientes/1/transacoes", &body))
+            .unwrap_or_else(|_| panic!("Request was refused"));
+        assert_eq!(transaction.descricao, "\u{e9}".repeat(10));
+    }
+
+    #[test]
+    fn non_ascii_descriptions_round_trip() {
+        let descriptions = ["café", "ação", "日本語", "🙂🙂", "ñ\u{e9}"];
+        // Timestamps are stored with microseconds
+        let realizada_em =
+            clock::timestamp::parse("2024-01-02T03:04:05.123456Z").expect("Invalid timestamp");
+        let mut transactions: [Transaction; 10] = Default::default();
+        transactions
+            .iter_mut()
+            .zip(descriptions)
+            .for_each(|(transaction, descricao)| {
+                transaction.valor = 1;
+                transaction.tipo = "c".to_string();
+                transaction.descricao = descricao.to_string();
+                transaction.realizada_em = realizada_em;
+            });
+
+        let encoded = encode_transactions(&transactions);
+        let mut decoded: [Transaction; 10] = Default::default();
+        decode_transactions(Some(encoded), &mut decoded);
+        transactions
+            .iter()
+            .zip(&decoded)
+            .for_each(|(transaction, decoded)| {
+                assert_eq!(decoded.descricao, transaction.descricao);
+                assert_eq!(decoded.valor, transaction.valor);
+                assert_eq!(decoded.tipo, transaction.tipo);
+                assert_eq!(decoded.realizada_em, transaction.realizada_em);
+            });
+    }
+
+    #[test]
+    fn transactions_are_stamped_with_the_clock() {
+        let now =
+            clock::timestamp::parse("2024-02-03T04:05:06.123456789Z").expect("Invalid timestamp");
+        let clock = FixedClock(now);
+        let request = post_request(
+            "/clientes/1/transacoes",
+            r#"{"valor":10,"tipo":"c","descricao":"abc"}"#,
+        );
+        let (_, transaction) = parse_request(&TransactionsConfig::default(), clock.now(), &request)
+            .unwrap_or_else(|_| panic!("Request was refused"));
+        assert_eq!(
+            serde_json::to_string(&transaction).expect("Failed to serialize transaction"),
+            r#"{"valor":10,"descricao":"abc","tipo":"c","realizada_em":"2024-02-03T04:05:06.123456Z"}"#
+        );
+    }
 }
@@ -1,4 +1,7 @@
-use crate::transaction::{self, Transaction};
+use crate::{
+    responses::ApiError,
+    transaction::{self, Transaction, ValidationError},
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -9,9 +12,10 @@ pub struct UserDb {
     pub transactions_count: i32,
     pub last_transaction: i32,
     pub encoded_transactions: Option<Vec<u8>>,
+    pub version: i64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     pub id: i32,
     pub balance_limit: i32,
@@ -19,6 +23,8 @@ pub struct User {
     pub transactions_count: i32,
     pub last_transaction: i32,
     pub transactions: [Transaction; 10],
+    // Bumped by every change of the stored row
+    pub version: i64,
 }
 
 impl From<User> for UserDb {
@@ -30,54 +36,90 @@ impl From<User> for UserDb {
             transactions_count: user.transactions_count,
             last_transaction: user.last_transaction,
             encoded_transactions: Some(transaction::encode_transactions(&user.transactions)),
+            version: user.version,
         };
     }
 }
 
 impl From<UserDb> for User {
     fn from(user: UserDb) -> Self {
-        let mut transactions: [Transaction; 10] = Default::default();
-        transaction::decode_transactions(user.encoded_transactions, &mut transactions);
-        return User {
-            id: user.id,
-            balance_limit: user.balance_limit,
-            balance: user.balance,
-            transactions_count: user.transactions_count,
-            last_transaction: user.last_transaction,
-            transactions,
-        };
+        return User::try_from_db(user).expect("Failed to decode transactions");
     }
 }
 
 pub enum TransactionResult {
     Ok,
     LimitExceeded,
-    InvalidTransactionKind(u8),
-    InvalidDescription,
+    // A credit would take the balance above i32::MAX
+    BalanceOutOfRange,
+    InvalidTransactionKind(String),
+}
+
+impl TransactionResult {
+    // The error sent back to the client, None when the transaction was applied
+    pub fn error(&self) -> Option<ApiError> {
+        return match self {
+            TransactionResult::Ok => None,
+            TransactionResult::LimitExceeded => Some(
+                ApiError::new("limit_exceeded", "Debit would exceed the account limit")
+                    .field("valor"),
+            ),
+            TransactionResult::BalanceOutOfRange => Some(
+                ApiError::new(
+                    "balance_out_of_range",
+                    format!("Credit would take the balance above {}", i32::MAX),
+                )
+                .field("valor"),
+            ),
+            TransactionResult::InvalidTransactionKind(tipo) => {
+                Some(ValidationError::InvalidTransactionKind(tipo.clone()).error())
+            }
+        };
+    }
 }
 
 impl User {
-    pub fn compute_transaction(&mut self, transaction: &Transaction) -> TransactionResult {
-        if transaction.descricao.len() > 10 || transaction.descricao.is_empty() {
-            return TransactionResult::InvalidDescription;
-        }
+    // Like From<UserDb>, without panicking on encoded_transactions that can't be decoded
+    pub fn try_from_db(user: UserDb) -> Result<User, String> {
+        let transactions = match &user.encoded_transactions {
+            Some(encoded_transactions) => {
+                transaction::try_decode_transactions(encoded_transactions)?
+            }
+            None => Default::default(),
+        };
+        return Ok(User {
+            id: user.id,
+            balance_limit: user.balance_limit,
+            balance: user.balance,
+            transactions_count: user.transactions_count,
+            last_transaction: user.last_transaction,
+            transactions,
+            version: user.version,
+        });
+    }
 
+    pub fn compute_transaction(&mut self, transaction: &Transaction) -> TransactionResult {
+        // The description was validated against the configured length with the request
         match transaction.tipo.as_str() {
             "c" => {
-                self.balance += transaction.valor;
+                let Some(balance) = self.balance.checked_add(transaction.valor) else {
+                    return TransactionResult::BalanceOutOfRange;
+                };
+                self.balance = balance;
                 return TransactionResult::Ok;
             }
             "d" => {
+                // A balance below i32::MIN is below any limit too
                 let limit = self.balance_limit;
-                if self.balance - transaction.valor < -limit {
-                    return TransactionResult::LimitExceeded;
-                }
-                self.balance -= transaction.valor;
+                let balance = match self.balance.checked_sub(transaction.valor) {
+                    Some(balance) if balance >= -limit => balance,
+                    _ => return TransactionResult::LimitExceeded,
+                };
+                self.balance = balance;
                 return TransactionResult::Ok;
             }
             tipo => {
-                let t = tipo.as_bytes()[0];
-                return TransactionResult::InvalidTransactionKind(t);
+                return TransactionResult::InvalidTransactionKind(tipo.to_string());
             }
         }
     }
@@ -86,8 +128,9 @@ impl User {
             valor: transaction.valor,
             descricao: transaction.descricao.clone(),
             tipo: transaction.tipo.clone(),
-            realizada_em: transaction.realizada_em.clone(),
+            realizada_em: transaction.realizada_em,
         };
+        self.version += 1;
         if self.transactions_count < 10 {
             let index: usize = self
                 .transactions_count
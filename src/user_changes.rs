@@ -0,0 +1,87 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::postgres::PgListener;
+
+use crate::{logging, replica::RecentWrites, statement_cache::StatementCache};
+
+// Channel the users_changed trigger notifies on
+const CHANNEL: &str = "users_changed";
+
+// A change to the users table announced by any writer, this instance or another
+pub enum Change {
+    // Truncated, every account may have changed
+    All,
+    // Inserted or updated with this version, None once the account was deleted
+    Account { id: i32, version: Option<i64> },
+}
+
+// Payloads are "id:version", "id" for a deleted account and "*" for all of them
+fn parse(payload: &str) -> Result<Change, String> {
+    if payload == "*" {
+        return Ok(Change::All);
+    }
+    let (id, version) = match payload.split_once(':') {
+        Some((id, version)) => (id, Some(version)),
+        None => (payload, None),
+    };
+    let id = id
+        .parse::<i32>()
+        .map_err(|_| return format!("Invalid {} notification {:?}", CHANNEL, payload))?;
+    let version = match version.map(str::parse::<i64>) {
+        Some(Ok(version)) => Some(version),
+        // Forgetting the account is always safe
+        Some(Err(_)) => {
+            logging::error!("Invalid {} notification {:?}", CHANNEL, payload);
+            None
+        }
+        None => None,
+    };
+    return Ok(Change::Account { id, version });
+}
+
+async fn receive(
+    database_url: &str,
+    statements: &StatementCache,
+    recent_writes: &RecentWrites,
+) -> Result<(), String> {
+    let mut listener = PgListener::connect(database_url)
+        .await
+        .map_err(|e| return format!("Error connecting {} listener: {}", CHANNEL, e))?;
+    listener
+        .listen(CHANNEL)
+        .await
+        .map_err(|e| return format!("Error listening on {}: {}", CHANNEL, e))?;
+    statements.start_listening();
+    recent_writes.start_listening();
+    logging::log!("Listening on {}", CHANNEL);
+    loop {
+        match listener.try_recv().await {
+            Ok(Some(notification)) => match parse(notification.payload()) {
+                Ok(change) => {
+                    statements.apply(&change);
+                    recent_writes.apply(&change);
+                }
+                Err(e) => logging::error!("{}", e),
+            },
+            Ok(None) => return Err(format!("{} listener disconnected", CHANNEL)),
+            Err(e) => return Err(format!("Error receiving notifications: {}", e)),
+        };
+    }
+}
+
+// Keeps the statement cache and the recent writes in sync with the changes of
+// every instance. Runs for as long as the process, reconnecting after failures.
+pub async fn listen(
+    database_url: String,
+    statements: Arc<StatementCache>,
+    recent_writes: Arc<RecentWrites>,
+) {
+    loop {
+        if let Err(e) = receive(&database_url, &statements, &recent_writes).await {
+            logging::error!("{}", e);
+        }
+        statements.stop_listening();
+        recent_writes.stop_listening();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,357 @@
+use crate::{
+    transaction::{self, Transaction},
+    user::UserDb,
+};
+
+pub struct Violation {
+    pub id: i32,
+    pub code: &'static str,
+    pub message: String,
+}
+
+fn violation(user: &UserDb, code: &'static str, message: String) -> Violation {
+    return Violation {
+        id: user.id,
+        code,
+        message,
+    };
+}
+
+// Slots of the ring that were never written hold the default transaction
+fn stored_transactions(transactions: &[Transaction; 10]) -> i32 {
+    let stored = transactions
+        .iter()
+        .filter(|transaction| return !transaction.tipo.is_empty())
+        .count();
+    return i32::try_from(stored).expect("ring holds 10 transactions");
+}
+
+// Invariants every account must hold after any sequence of transactions.
+// ledger_balance is the sum of the account's ledger entries, when there is a ledger.
+pub fn check_user(user: &UserDb, ledger_balance: Option<i64>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    // In i64, negating i32::MIN overflows
+    let lowest_balance = -i64::from(user.balance_limit);
+    if i64::from(user.balance) < lowest_balance {
+        violations.push(violation(
+            user,
+            "balance_below_limit",
+            format!(
+                "balance {} is below the limit {}",
+                user.balance, lowest_balance
+            ),
+        ));
+    }
+
+    let count_in_range = (0..=10).contains(&user.transactions_count);
+    if !count_in_range {
+        violations.push(violation(
+            user,
+            "transactions_count_out_of_range",
+            format!(
+                "transactions_count {} is not between 0 and 10",
+                user.transactions_count
+            ),
+        ));
+    }
+    if !(0..10).contains(&user.last_transaction) {
+        violations.push(violation(
+            user,
+            "last_transaction_out_of_range",
+            format!(
+                "last_transaction {} is not between 0 and 9",
+                user.last_transaction
+            ),
+        ));
+    } else if user.transactions_count < 10 && user.last_transaction != user.transactions_count {
+        // Until the ring is full the next slot is always the one after the last written
+        violations.push(violation(
+            user,
+            "ring_position_mismatch",
+            format!(
+                "last_transaction {} doesn't follow transactions_count {}",
+                user.last_transaction, user.transactions_count
+            ),
+        ));
+    }
+
+    let stored = match &user.encoded_transactions {
+        Some(encoded) => match transaction::try_decode_transactions(encoded) {
+            Ok(transactions) => Some(stored_transactions(&transactions)),
+            Err(e) => {
+                violations.push(violation(
+                    user,
+                    "undecodable_transactions",
+                    format!("encoded_transactions can't be decoded: {}", e),
+                ));
+                None
+            }
+        },
+        None => Some(0),
+    };
+    if let (Some(stored), true) = (stored, count_in_range) {
+        if stored != user.transactions_count {
+            violations.push(violation(
+                user,
+                "transactions_count_mismatch",
+                format!(
+                    "transactions_count is {} but {} transactions are stored",
+                    user.transactions_count, stored
+                ),
+            ));
+        }
+    }
+
+    if let Some(ledger_balance) = ledger_balance {
+        if ledger_balance != i64::from(user.balance) {
+            violations.push(violation(
+                user,
+                "ledger_mismatch",
+                format!(
+                    "balance {} doesn't match the ledger sum {}",
+                    user.balance, ledger_balance
+                ),
+            ));
+        }
+    }
+    return violations;
+}
+
+// Returns the account with its repairable violations fixed, or None when there is
+// nothing to fix. Undecodable history is dropped, the ring indices are rebuilt
+// from the stored transactions and the ledger wins over the balance. A balance
+// below the limit without a ledger can't be repaired.
+pub fn repair_user(user: &UserDb, ledger_balance: Option<i64>) -> Option<UserDb> {
+    if check_user(user, ledger_balance).is_empty() {
+        return None;
+    }
+    let transactions = match &user.encoded_transactions {
+        Some(encoded) => transaction::try_decode_transactions(encoded).unwrap_or_default(),
+        None => Default::default(),
+    };
+    let transactions_count = stored_transactions(&transactions);
+    let last_transaction = if transactions_count < 10 {
+        transactions_count
+    } else if (0..10).contains(&user.last_transaction) {
+        user.last_transaction
+    } else {
+        0
+    };
+    let balance = ledger_balance
+        .and_then(|balance| return i32::try_from(balance).ok())
+        .unwrap_or(user.balance);
+
+    let repaired = UserDb {
+        id: user.id,
+        balance_limit: user.balance_limit,
+        balance,
+        transactions_count,
+        last_transaction,
+        encoded_transactions: Some(transaction::encode_transactions(&transactions)),
+        version: user.version,
+    };
+    if repaired.balance == user.balance
+        && repaired.transactions_count == user.transactions_count
+        && repaired.last_transaction == user.last_transaction
+        && repaired.encoded_transactions == user.encoded_transactions
+    {
+        return None;
+    }
+    return Some(repaired);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+
+    fn transaction(valor: i32) -> Transaction {
+        return Transaction {
+            valor,
+            descricao: "teste".to_string(),
+            tipo: "c".to_string(),
+            realizada_em: clock::timestamp::parse("2024-01-02T03:04:05.123456Z")
+                .expect("Invalid timestamp"),
+        };
+    }
+
+    // Encoded ring with the first `count` slots written
+    fn ring(count: usize) -> Vec<u8> {
+        let mut transactions: [Transaction; 10] = Default::default();
+        transactions
+            .iter_mut()
+            .take(count)
+            .enumerate()
+            .for_each(|(i, slot)| *slot = transaction(i32::try_from(i).unwrap() + 1));
+        return transaction::encode_transactions(&transactions);
+    }
+
+    // Account with 3 credits summing up to its balance
+    fn user() -> UserDb {
+        return UserDb {
+            id: 1,
+            balance_limit: 1000,
+            balance: 6,
+            transactions_count: 3,
+            last_transaction: 3,
+            encoded_transactions: Some(ring(3)),
+            version: 3,
+        };
+    }
+
+    fn codes(user: &UserDb, ledger_balance: Option<i64>) -> Vec<&'static str> {
+        return check_user(user, ledger_balance)
+            .iter()
+            .map(|violation| return violation.code)
+            .collect();
+    }
+
+    #[test]
+    fn consistent_accounts_have_no_violations() {
+        assert!(codes(&user(), Some(6)).is_empty());
+        let new_user = UserDb {
+            balance: 0,
+            transactions_count: 0,
+            last_transaction: 0,
+            encoded_transactions: None,
+            ..user()
+        };
+        assert!(codes(&new_user, None).is_empty());
+        let full_ring = UserDb {
+            transactions_count: 10,
+            last_transaction: 4,
+            encoded_transactions: Some(ring(10)),
+            ..user()
+        };
+        assert!(codes(&full_ring, None).is_empty());
+        assert!(repair_user(&user(), Some(6)).is_none());
+    }
+
+    #[test]
+    fn balance_below_limit() {
+        let below = UserDb {
+            balance: -1001,
+            ..user()
+        };
+        assert_eq!(codes(&below, None), ["balance_below_limit"]);
+        let at_limit = UserDb {
+            balance: -1000,
+            ..user()
+        };
+        assert!(codes(&at_limit, None).is_empty());
+        // Can't be repaired without a ledger
+        assert!(repair_user(&below, None).is_none());
+    }
+
+    #[test]
+    fn extreme_limits_dont_overflow() {
+        let lowest_limit = UserDb {
+            balance_limit: i32::MIN,
+            balance: 0,
+            ..user()
+        };
+        assert_eq!(codes(&lowest_limit, None), ["balance_below_limit"]);
+        let highest_limit = UserDb {
+            balance_limit: i32::MAX,
+            balance: i32::MIN,
+            ..user()
+        };
+        assert_eq!(codes(&highest_limit, None), ["balance_below_limit"]);
+    }
+
+    #[test]
+    fn transactions_count_out_of_range() {
+        let out_of_range = UserDb {
+            transactions_count: 11,
+            ..user()
+        };
+        assert_eq!(
+            codes(&out_of_range, None),
+            ["transactions_count_out_of_range"]
+        );
+        let repaired = repair_user(&out_of_range, None).expect("Nothing repaired");
+        assert_eq!(repaired.transactions_count, 3);
+        assert_eq!(repaired.last_transaction, 3);
+        assert!(codes(&repaired, None).is_empty());
+    }
+
+    #[test]
+    fn last_transaction_out_of_range() {
+        let out_of_range = UserDb {
+            transactions_count: 10,
+            last_transaction: 10,
+            encoded_transactions: Some(ring(10)),
+            ..user()
+        };
+        assert_eq!(
+            codes(&out_of_range, None),
+            ["last_transaction_out_of_range"]
+        );
+        // A full ring starts over from the first slot
+        let repaired = repair_user(&out_of_range, None).expect("Nothing repaired");
+        assert_eq!(repaired.transactions_count, 10);
+        assert_eq!(repaired.last_transaction, 0);
+        assert!(codes(&repaired, None).is_empty());
+    }
+
+    #[test]
+    fn ring_position_mismatch() {
+        let mismatched = UserDb {
+            last_transaction: 5,
+            ..user()
+        };
+        assert_eq!(codes(&mismatched, None), ["ring_position_mismatch"]);
+        let repaired = repair_user(&mismatched, None).expect("Nothing repaired");
+        assert_eq!(repaired.last_transaction, 3);
+        assert_eq!(repaired.encoded_transactions, user().encoded_transactions);
+        assert!(codes(&repaired, None).is_empty());
+    }
+
+    #[test]
+    fn transactions_count_mismatch() {
+        let mismatched = UserDb {
+            transactions_count: 2,
+            last_transaction: 2,
+            ..user()
+        };
+        assert_eq!(codes(&mismatched, None), ["transactions_count_mismatch"]);
+        let repaired = repair_user(&mismatched, None).expect("Nothing repaired");
+        assert_eq!(repaired.transactions_count, 3);
+        assert_eq!(repaired.last_transaction, 3);
+        assert!(codes(&repaired, None).is_empty());
+    }
+
+    #[test]
+    fn undecodable_transactions() {
+        let undecodable = UserDb {
+            encoded_transactions: Some(vec![1, 2, 3]),
+            ..user()
+        };
+        assert_eq!(codes(&undecodable, None), ["undecodable_transactions"]);
+        // The history is dropped, the balance is kept
+        let repaired = repair_user(&undecodable, None).expect("Nothing repaired");
+        assert_eq!(repaired.balance, 6);
+        assert_eq!(repaired.transactions_count, 0);
+        assert_eq!(repaired.last_transaction, 0);
+        assert_eq!(repaired.encoded_transactions, Some(ring(0)));
+        assert!(codes(&repaired, None).is_empty());
+    }
+
+    #[test]
+    fn ledger_mismatch() {
+        assert_eq!(codes(&user(), Some(7)), ["ledger_mismatch"]);
+        // The ledger wins over the balance
+        let repaired = repair_user(&user(), Some(7)).expect("Nothing repaired");
+        assert_eq!(repaired.balance, 7);
+        assert_eq!(repaired.version, 3);
+        assert!(codes(&repaired, Some(7)).is_empty());
+
+        let below = UserDb {
+            balance: -2000,
+            ..user()
+        };
+        let repaired = repair_user(&below, Some(-500)).expect("Nothing repaired");
+        assert_eq!(repaired.balance, -500);
+        assert!(codes(&repaired, Some(-500)).is_empty());
+    }
+}
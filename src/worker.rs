@@ -0,0 +1,163 @@
+use std::{os::unix::net::UnixListener, sync::Arc, time::Duration};
+
+use crate::{
+    account_queue::AccountQueues,
+    auth::ApiKeyCache,
+    circuit_breaker::CircuitBreaker,
+    clock::Clock,
+    config::Config,
+    db,
+    group_commit::GroupCommit,
+    listener, logging,
+    metrics::MetricsRegistry,
+    rate_limit::RateLimiter,
+    replica::{ReadReplica, RecentWrites},
+    signing::{self, NonceCache},
+    state::AppState,
+    statement_cache::StatementCache,
+    tls::TlsReloader,
+    user_changes,
+};
+
+// What every worker of the process shares
+#[derive(Clone)]
+pub struct Shared {
+    pub config: Arc<Config>,
+    pub api_keys: Arc<ApiKeyCache>,
+    pub nonces: Arc<NonceCache>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub metrics: Arc<MetricsRegistry>,
+    pub statements: Arc<StatementCache>,
+    pub circuit_breaker: Arc<CircuitBreaker>,
+    pub recent_writes: Arc<RecentWrites>,
+    pub clock: Arc<dyn Clock>,
+    pub tls: Option<Arc<TlsReloader>>,
+}
+
+pub fn new_runtime() -> tokio::runtime::Runtime {
+    return tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build tokio runtime");
+}
+
+// Runs one worker on the current thread until its listeners stop
+pub fn run(worker_id: usize, shared: Shared, unix_listener: Option<UnixListener>) {
+    new_runtime().block_on(serve(worker_id, shared, unix_listener));
+}
+
+async fn serve(worker_id: usize, shared: Shared, unix_listener: Option<UnixListener>) {
+    let config = &shared.config;
+    let workers = config.server.workers;
+    let max_connections =
+        config.database.max_connections / u32::try_from(workers).unwrap_or(u32::MAX);
+    logging::log!("Worker {worker_id} using {max_connections} database connections");
+
+    let pool = match db::connect_with_retries(config, max_connections).await {
+        Ok(pool) => Arc::new(pool),
+        Err(e) => {
+            eprintln!("Worker {worker_id}: {e}");
+            std::process::exit(1);
+        }
+    };
+    let replica = match &config.database.read_url {
+        Some(url) => match db::connect_replica(config, url, max_connections) {
+            Ok(pool) => Some(ReadReplica::new(pool, shared.recent_writes.clone())),
+            Err(e) => {
+                eprintln!("Worker {worker_id}: read replica: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let state = Arc::new(AppState {
+        worker_id,
+        account_queues: AccountQueues::new(pool.clone(), config.storage.queue_max_batch),
+        group_commit: GroupCommit::new(
+            pool.clone(),
+            Duration::from_millis(config.storage.group_window_ms),
+            config.storage.group_max_size,
+        ),
+        pool,
+        config: shared.config.clone(),
+        api_keys: shared.api_keys,
+        nonces: shared.nonces,
+        rate_limiter: shared.rate_limiter,
+        metrics: shared.metrics,
+        statements: shared.statements,
+        circuit_breaker: shared.circuit_breaker,
+        recent_writes: shared.recent_writes,
+        replica,
+        clock: shared.clock,
+    });
+
+    // The cache and the recent writes are shared, one worker is enough to
+    // receive the changes of every instance
+    if worker_id == 0 && (state.config.statement_cache.enabled || state.replica.is_some()) {
+        tokio::spawn(user_changes::listen(
+            state.config.database_url().to_string(),
+            state.statements.clone(),
+            state.recent_writes.clone(),
+        ));
+    }
+
+    // The nonces are shared, one worker is enough to delete the expired ones
+    if worker_id == 0 && state.config.signing.enabled && state.config.signing.shared {
+        let interval = Duration::from_secs(state.config.signing.max_skew_secs.max(1));
+        tokio::spawn(signing::expire_nonces(
+            state.pool.clone(),
+            state.clock.clone(),
+            interval,
+        ));
+    }
+
+    // The reloader is shared, one worker is enough to watch the files
+    if let (0, Some(tls)) = (worker_id, &shared.tls) {
+        let reload_interval = Duration::from_secs(state.config.tls.reload_interval_secs);
+        tokio::spawn(tls.clone().watch(reload_interval));
+    }
+
+    let mut servers = Vec::new();
+    for listener_config in state.config.tcp_listeners() {
+        let listener =
+            match listener::bind_tcp(&listener_config, state.config.server.backlog, workers > 1) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Worker {worker_id}: failed to start TCP listener: {e}");
+                    std::process::exit(1);
+                }
+            };
+        logging::log!(
+            "Worker {} listening for {:?} connections on {}",
+            worker_id,
+            listener_config.routes,
+            listener_config.address
+        );
+        servers.push(tokio::spawn(listener::serve_tcp(
+            listener,
+            listener_config.routes,
+            state.clone(),
+            shared.tls.clone(),
+        )));
+    }
+    if let Some(unix_listener) = unix_listener {
+        let listener = match tokio::net::UnixListener::from_std(unix_listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Worker {worker_id}: failed to start unix socket listener: {e}");
+                std::process::exit(1);
+            }
+        };
+        servers.push(tokio::spawn(listener::serve_unix(
+            listener,
+            state.clone(),
+            shared.tls.clone(),
+        )));
+    }
+    for server in servers {
+        if let Err(e) = server.await {
+            logging::error!("Worker {} listener stopped: {}", worker_id, e);
+        }
+    }
+    logging::log!("Worker {worker_id} stopped");
+}